@@ -13,11 +13,107 @@ import shutil
 import requests
 from dateutil.parser import parse as parsedate
 import os
+import sys
+import signal
+import itertools
+import hashlib
+import base64
+import tempfile
+import contextlib
+import io
+import time
+import glob
+import gzip
+import fcntl
+import sqlite3
+import random
+from datetime import datetime, timezone
 from multiprocessing import Pool, get_context
-from functools import partial
+from multiprocessing.pool import ThreadPool
+import threading
+from functools import partial, cmp_to_key
 import multiprocessing
 
 
+# exit codes
+EXIT_ERROR = 1  # other errors, or --check found differences
+EXIT_CONFIG = 2  # invalid configuration
+EXIT_NETWORK = 3  # crates.io or GitHub unreachable, or request limits exceeded
+EXIT_RESOLUTION = 4  # the selection of crates failed a check
+EXIT_IO = 5  # file or git errors
+EXIT_TIMEOUT = 6  # --max-duration exceeded
+
+# warnings and errors as GitHub Actions annotations, set by --github-annotations
+GITHUB_ANNOTATIONS = False
+
+
+def annotation(level, message):
+    """
+    Format a message as `LEVEL: message`, or with --github-annotations as a workflow command of this level.
+    """
+    if GITHUB_ANNOTATIONS:
+        message = str(message).replace("%", "%25").replace("\r", "%0D").replace("\n", "%0A")
+        return f"::{level}::{message}"
+    return f"{level.upper()}: {message}"
+
+
+def fail(code, message):
+    """
+    Print an error message and exit with one of the EXIT_* codes.
+    """
+    print(annotation("error", message), file=sys.stderr)
+    sys.exit(code)
+
+
+def to_toml(data):
+    """
+    Serialize a table of tables and arrays of tables whose values are strings, numbers, booleans or lists of strings.
+    TOML has no null: None values are left out.
+    """
+
+    def _value(v):
+        if isinstance(v, bool):
+            return "true" if v else "false"
+        if isinstance(v, (int, float)):
+            return str(v)
+        if isinstance(v, list):
+            return "[" + ", ".join(_value(i) for i in v) + "]"
+        # a JSON string is a valid TOML basic string
+        return json.dumps(str(v), ensure_ascii=False)
+
+    def _table(t):
+        def _key(k):
+            return k if re.match(r"^[A-Za-z0-9_-]+$", k) else json.dumps(k)
+
+        return "".join(f"{_key(k)} = {_value(v)}\n" for k, v in t.items() if v is not None)
+
+    lines = []
+    for key, value in data.items():
+        if isinstance(value, list):
+            lines.extend(f"[[{key}]]\n{_table(t)}" for t in value)
+        else:
+            lines.append(f"[{key}]\n{_table(value)}")
+    return "\n".join(lines)
+
+
+def warn(message):
+    """
+    Print a warning, or with --github-annotations a [workflow command](https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions#setting-a-warning-message) shown in the run summary.
+    """
+    print(annotation("warning", message))
+
+
+def output_time():
+    """
+    Return the time to write in the generated files: SOURCE_DATE_EPOCH if set, for reproducible outputs, or now.
+    See https://reproducible-builds.org/specs/source-date-epoch/
+    """
+    epoch = os.environ.get("SOURCE_DATE_EPOCH")
+    if epoch:
+        return datetime.fromtimestamp(int(epoch), timezone.utc)
+    return datetime.now(timezone.utc)
+
+
 class SemVer:
     # regex from https://github.com/python-semver/python-semver
     _REGEX = re.compile(
@@ -120,6 +216,17 @@ class SemVer:
 
         return rccmp
 
+    @staticmethod
+    def sorted(versions):
+        """Sort version strings in SemVer order."""
+        return sorted(versions, key=cmp_to_key(lambda a, b: SemVer(a).compare(b)))
+
+    @staticmethod
+    def compatibility(version):
+        """Return the semver-compatible range of a version string: 1.2.3 -> "1", 0.2.3 -> "0.2", 0.0.3 -> "0.0.3"."""
+        major, minor, patch = SemVer(version).parts[:3]
+        return f"{major}" if major else f"0.{minor}" if minor else f"0.0.{patch}"
+
     def _caret_requirement(pattern):
         """
         Match a [caret-requirement](https://doc.rust-lang.org/cargo/reference/specifying-dependencies.html#caret-requirements).
@@ -292,7 +399,7 @@ class SemVer:
 
             if not m:
                 m = (None, last)
-                print(f"WARNING: no matching version found, using latest version {m[1]['name']} {pattern}")
+                warn(f"no matching version found, using latest version {m[1]['name']} {pattern}")
 
             return m[1]
 
@@ -302,35 +409,412 @@ class SemVer:
             raise e
 
 
+class Http:
+    """
+    HTTP client that accounts requests and bytes, to stay within the [crates.io crawler policy](https://crates.io/policies#crawlers).
+    """
+
+    def __init__(self, max_requests=0, max_bytes=0, interval=0):
+        self.max_requests = max_requests
+        self.max_bytes = max_bytes
+        self.interval = interval  # minimum delay in seconds between two requests, shared by all the threads
+        self.requests = 0
+        self.bytes = 0
+        self.last_request = 0
+        self.record_dir = None  # save the responses into this directory
+        self.replay_dir = None  # serve the responses from this directory instead of the network
+        self.lock = threading.Lock()  # requests may be sent by several threads
+
+    def get(self, url, **kwargs):
+        """
+        GET an URL, aborting the run if the configured limits are exceeded.
+        """
+        with self.lock:
+            if self.max_requests and self.requests >= self.max_requests:
+                fail(EXIT_NETWORK, f"too many requests ({self.requests}), aborted before {url}")
+            if self.max_bytes and self.bytes >= self.max_bytes:
+                fail(EXIT_NETWORK, f"too many bytes downloaded ({self.bytes}), aborted before {url}")
+            self.requests += 1
+            if self.interval and not self.replay_dir:
+                delay = self.last_request + self.interval - time.monotonic()
+                if delay > 0:
+                    time.sleep(delay)
+                self.last_request = time.monotonic()
+
+        if self.replay_dir:
+            return self._replay(url, kwargs.get("headers"))
+
+        r = requests.get(url, **kwargs)
+        with self.lock:
+            self.bytes += len(r.content)
+
+        if self.record_dir:
+            self._record(url, kwargs.get("headers"), r)
+        return r
+
+    def _cassette(self, directory, url, headers=None):
+        """
+        Return the file of the response to an URL. The request headers are part of the key, e.g. conditional requests.
+        """
+        key = url + "".join(f"\n{k}: {v}" for k, v in sorted((headers or {}).items()))
+        return Path(directory) / (hashlib.sha256(key.encode()).hexdigest() + ".json")
+
+    def _record(self, url, headers, r):
+        """
+        Save a response.
+        """
+        Path(self.record_dir).mkdir(parents=True, exist_ok=True)
+        data = {
+            "url": url,
+            "status_code": r.status_code,
+            "headers": dict(r.headers),
+            "content": base64.b64encode(r.content).decode(),
+        }
+        json.dump(data, open(self._cassette(self.record_dir, url, headers), "w"), indent=2)
+
+    def _replay(self, url, headers=None):
+        """
+        Return a recorded response.
+        """
+        cassette = self._cassette(self.replay_dir, url, headers)
+        if not cassette.is_file():
+            fail(EXIT_NETWORK, f"no recorded response for {url}")
+        data = json.load(open(cassette))
+        r = requests.Response()
+        r.url = url
+        r.status_code = data["status_code"]
+        r.headers = requests.structures.CaseInsensitiveDict(data["headers"])
+        r._content = base64.b64decode(data["content"])
+        r.encoding = "utf-8"
+        return r
+
+
+class CratesIoApi:
+    """
+    Source of the top crates: the crates.io API.
+    """
+
+    def __init__(self, http, api_url="https://crates.io", workers=4):
+        self.http = http
+        self.api_url = api_url
+        self.workers = workers
+
+    def fetch(self, count, category="", verbose=False):
+        """
+        Use the crates.io API to fetch crates per download count.
+        The pages are fetched concurrently, the crates are returned in the rank order.
+        """
+        where = f" in {category}" if category else ""
+        if category:
+            category = f"&category={category}"
+
+        if count <= 0:
+            return []
+
+        # the same page size for all pages, otherwise the offsets are wrong
+        per_page = min(count, 100)
+
+        def _url(page):
+            return f"{self.api_url}/api/v1/crates?page={page}&per_page={per_page}&sort=downloads{category}"
+
+        def _get(url):
+            try:
+                data = self.http.get(url).json()
+                if not isinstance(data.get("crates"), list):
+                    fail(EXIT_NETWORK, f"unexpected crates.io response, no list of crates: {url}")
+            except SystemExit as e:
+                # the pool would lose it: re-raised below, in the main thread
+                return e
+            if verbose:
+                print(url, len(data["crates"]))
+            return data
+
+        # the first page tells how many crates there are: do not ask for empty pages
+        first = _get(_url(1))
+        if isinstance(first, SystemExit):
+            raise first
+        meta = first.get("meta", {})
+        if meta.get("total") is not None:
+            count = min(count, meta["total"])
+        elif not meta.get("next_page"):
+            count = min(count, len(first["crates"]))
+
+        urls = [_url(page) for page in range(2, (count + per_page - 1) // per_page + 1)]
+
+        if self.workers > 1 and len(urls) > 1:
+            with ThreadPool(min(self.workers, len(urls))) as pool:
+                pages = pool.map(_get, urls)
+        else:
+            pages = list(map(_get, urls))
+
+        crates = list(first["crates"])
+        for page in pages:
+            if isinstance(page, SystemExit):
+                raise page
+            crates.extend(page["crates"])
+
+        if len(crates) < count:
+            warn(f"only {len(crates)} crates{where}, {count} expected")
+
+        return crates[:count]
+
+
+class LocalFile:
+    """
+    Source of the top crates: a JSON file with the same shape as the crates.io API response.
+    """
+
+    def __init__(self, filename):
+        self.crates = json.load(open(filename))["crates"]
+
+    def fetch(self, count, category="", verbose=False):
+        """
+        Return the first crates of the file, optionally filtered by category.
+        """
+        if category:
+            return [crate for crate in self.crates if category in (crate.get("categories") or [])][:count]
+        return self.crates[:count]
+
+
 class TopCrates:
     """
     Class to download crates and make a local Rust registry.
     """
 
-    def __init__(self):
+    SUMMARY_FIELDS = (
+        "downloads",
+        "recent_downloads",
+        "max_version",
+        "description",
+        "repository",
+        "homepage",
+        "documentation",
+    )
+
+    def __init__(self, config_file="top-crates.toml", index_dir=None):
         """
         Constructor.
+        The configuration file and the crates.io index directory can be overridden, e.g. for tests.
         """
         self.verbose = False
+        self.save_caches = True  # False for the read-only runs, e.g. --check
         self.crates = defaultdict(set)
         self.selected_crates = None
-
-        conf = tomli.load(open("top-crates.toml", "rb"))
+        self.origins = {}
+        self.summaries = {}  # fields of the crates.io summaries
+        self.categories = defaultdict(set)
+        self.excluded = {}
+        self.replacements = []  # versions replaced by --audit-fix
+        self.spellings = {}
+        self.graph = defaultdict(set)
+        self.requested = []  # the roots of the graph
+        self.requests = {}  # the requested versions of the roots
+        self.locked = defaultdict(set)
+
+        config_file = Path(config_file)
+        conf = tomli.load(open(config_file, "rb"))
+
+        self.conf_index = conf.get("index", "crates.io-index")
+        self.conf_index_url = conf.get("index-url", "https://github.com/rust-lang/crates.io-index")
+        self.conf_api_url = conf.get("api-url", "https://crates.io")
+        self.conf_download_url = conf.get(
+            "download-url", "https://static.crates.io/crates/{crate}/{crate}-{version}.crate"
+        )
+        self.index_dir = Path(index_dir or self.conf_index)
 
         self.conf_top_crates = conf.get("top-crates", 0)
+        self.conf_top_reverse_dependencies = conf.get("top-reverse-dependencies", 0)
         self.conf_categories = conf.get("categories", [])
         self.conf_cookbook = bool(conf.get("cookbook", False))
         self.conf_additions = conf.get("additions", [])
         self.conf_commands = conf.get("commands", [])
+        self.conf_hidden_additions = conf.get("hidden-additions", [])
+        self.conf_core = conf.get("core", [])
+        self.conf_core_top = conf.get("core-top", 0)
+        self.conf_max_duplicate_versions = conf.get("max-duplicate-versions")
+        self.conf_checksum = conf.get("checksum", "sha256")
+        self.conf_excluded_owners = conf.get("excluded-owners", [])
+        self.conf_min_downloads = conf.get("min-downloads", 0)
+        self.conf_max_crate_size = conf.get("max-crate-size", 0)
+        self.conf_missing_versions = conf.get("missing-versions", "error")
+        self.conf_version_age_warning = conf.get("version-age-warning", 3 * 365)
+        self.conf_max_version_age = conf.get("max-version-age", 0)
+        self.conf_version_settle_days = conf.get("version-settle-days", 0)
+        self.conf_zero_major = conf.get("zero-major", "")
+        self.conf_force_versions = conf.get("force-versions", {})
+        self.conf_aliases = conf.get("aliases", {})
+        self.conf_min_crates = conf.get("min-crates", 1)
+        self.conf_max_depth = conf.get("max-depth", 0)
+        self.conf_exclude_edges = conf.get("exclude-edges", [])
+        self.conf_heavy_crates = conf.get("heavy-crates", [])
+        self.conf_max_requests = conf.get("max-requests", 0)
+        self.conf_max_bytes = conf.get("max-bytes", 0)
+        self.conf_concurrent_requests = conf.get("concurrent-requests", 4)
+        self.conf_request_interval = conf.get("request-interval", 1)
+        self.conf_require_curated = bool(conf.get("require-curated", False))
+
+        for pattern in conf.get("include", []):
+            # Path.glob() only takes relative patterns
+            if Path(pattern).is_absolute():
+                include_files = map(Path, glob.glob(pattern))
+            else:
+                include_files = config_file.parent.glob(pattern)
+            for include_file in sorted(include_files):
+                try:
+                    include = tomli.load(open(include_file, "rb"))
+                except tomli.TOMLDecodeError as e:
+                    fail(EXIT_CONFIG, f"cannot parse included file {include_file}: {e}")
+                self.conf_additions += include.get("additions", [])
+                self.conf_commands += include.get("commands", [])
+
+        self.conf_nightly_only = conf.get("nightly-only", [])
+        self.conf_exclusions = conf.get("exclusions", [])
+        self.conf_deny_by_default = bool(conf.get("deny-by-default", False))
+        self.conf_allow = conf.get("allow", [])
+
+        # names and patterns are compiled only from a valid configuration, validate() reports the errors
+        self.nightly_only, self.exclusions, self.allow, self.aliases = set(), [], [], {}
+        if not self.validate():
+            self.compile()
+
+        self.http = Http(self.conf_max_requests, self.conf_max_bytes, self.conf_request_interval)
+        self.source = CratesIoApi(self.http, self.conf_api_url, self.conf_concurrent_requests)
+
+    def config(self):
+        """
+        Return the effective configuration, after includes are merged.
+        """
+        return dict((k[5:].replace("_", "-"), v) for k, v in vars(self).items() if k.startswith("conf_"))
 
+    def compile(self):
+        """
+        Compile the crate names and the patterns of the configuration.
+        """
+        self.nightly_only = set(TopCrates._normalize_name(k) for k in self.conf_nightly_only)
         self.exclusions = [
-            re.compile("^" + re.escape(k).replace(r"\*", r".*") + "$") for k in conf.get("exclusions", [])
+            re.compile("^" + re.escape(TopCrates._normalize_name(k)).replace(r"\*", r".*") + "$")
+            for k in self.conf_exclusions
+        ]
+        self.allow = [
+            re.compile("^" + re.escape(TopCrates._normalize_name(k)).replace(r"\*", r".*") + "$")
+            for k in self.conf_allow
         ]
+        self.aliases = dict(
+            (TopCrates._normalize_name(k), TopCrates._normalize_name(v)) for k, v in self.conf_aliases.items()
+        )
+
+    def validate(self):
+        """
+        Check the configuration values. Return all the errors at once.
+        """
+        errors = []
+        conf = self.config()
+
+        for key in (
+            "additions",
+            "commands",
+            "hidden-additions",
+            "core",
+            "nightly-only",
+            "heavy-crates",
+            "exclusions",
+            "allow",
+            "excluded-owners",
+            "categories",
+            "exclude-edges",
+        ):
+            if not isinstance(conf[key], list):
+                errors.append(f"{key}: {conf[key]!r} is not an array")
+                conf[key] = []
+        for key in ("force-versions", "aliases"):
+            if not isinstance(conf[key], dict):
+                errors.append(f"{key}: {conf[key]!r} is not a table")
+                conf[key] = {}
+
+        for key in ("additions", "commands", "hidden-additions", "core", "nightly-only", "heavy-crates"):
+            for name in conf[key]:
+                if not isinstance(name, str) or not re.match(r"^[A-Za-z0-9_-]+$", name):
+                    errors.append(f"{key}: invalid crate name {name!r}")
+
+        for key in ("exclusions", "allow", "excluded-owners"):
+            for pattern in conf[key]:
+                if not isinstance(pattern, str) or not re.match(r"^[A-Za-z0-9_*:-]+$", pattern):
+                    errors.append(f"{key}: invalid pattern {pattern!r}")
+
+        for key in (
+            "top-crates",
+            "top-reverse-dependencies",
+            "core-top",
+            "min-downloads",
+            "max-crate-size",
+            "version-age-warning",
+            "max-version-age",
+            "version-settle-days",
+            "min-crates",
+            "max-depth",
+            "max-requests",
+            "max-bytes",
+        ):
+            if not isinstance(conf[key], int) or isinstance(conf[key], bool) or conf[key] < 0:
+                errors.append(f"{key}: {conf[key]!r} is not a non-negative integer")
+        interval = conf["request-interval"]
+        if not isinstance(interval, (int, float)) or isinstance(interval, bool) or interval < 0:
+            errors.append(f"request-interval: {interval!r} is not a non-negative number of seconds")
+        if not isinstance(conf["concurrent-requests"], int) or conf["concurrent-requests"] < 1:
+            errors.append(f"concurrent-requests: {conf['concurrent-requests']!r} is not a positive integer")
+        if conf["max-duplicate-versions"] is not None and (
+            not isinstance(conf["max-duplicate-versions"], int) or conf["max-duplicate-versions"] < 0
+        ):
+            errors.append(f"max-duplicate-versions: {conf['max-duplicate-versions']!r} is not a non-negative integer")
+
+        for category in conf["categories"]:
+            if not isinstance(category, dict) or not all(isinstance(n, int) and n >= 0 for n in category.values()):
+                errors.append(f"categories: {category!r} is not a table of category names to numbers of crates")
+
+        for key, choices in (("zero-major", ("", "warn", "exclude")), ("missing-versions", ("error", "latest"))):
+            if conf[key] not in choices:
+                errors.append(f"{key}: {conf[key]!r} is not one of {', '.join(repr(c) for c in choices)}")
+        if conf["checksum"] not in hashlib.algorithms_available or conf["checksum"].startswith("shake_"):
+            errors.append(f"checksum: unknown hash algorithm {conf['checksum']!r}")
+
+        for name, version in conf["force-versions"].items():
+            if not isinstance(version, str) or not SemVer._REGEX.match(version):
+                errors.append(f"force-versions: {name} has an invalid version {version!r}")
+        try:
+            TopCrates._crate_url(conf["download-url"], "serde", "1.0.0")
+        except (ValueError, TypeError) as e:
+            errors.append(f"download-url: {e}")
+        for old, new in conf["aliases"].items():
+            if not isinstance(new, str) or not re.match(r"^[A-Za-z0-9_-]+$", new):
+                errors.append(f"aliases: {old} has an invalid new name {new!r}")
+        for edge in conf["exclude-edges"]:
+            if not isinstance(edge, list) or len(edge) != 2 or not all(isinstance(name, str) for name in edge):
+                errors.append(f"exclude-edges: {edge!r} is not a pair of crate names")
+
+        return errors
+
+    def load_lockfile(self, filename):
+        """
+        Load the versions of a Cargo.lock, to prefer them when they match requirements.
+        """
+        lockfile = tomli.load(open(filename, "rb"))
+        for package in lockfile.get("package", []):
+            if package.get("source", "").startswith("registry+"):
+                self.locked[TopCrates._normalize_name(package["name"])].add(package["version"])
 
     def load(self, filename):
         """
         Load crates from a JSON file.
         """
+        # the origins first, add() filters on them
+        meta_file = Path(filename).with_suffix(".meta.json")
+        if meta_file.is_file():
+            meta = json.load(open(meta_file))
+            self.origins.update(meta.get("origins", {}))
+            self.summaries.update(meta.get("summaries", {}))
+            for k, v in meta.get("categories", {}).items():
+                self.categories[k].update(v)
+
         data = json.load(open(filename))
         for k, v in data.items():
             for version in v:
@@ -338,79 +822,314 @@ class TopCrates:
 
     def save(self, filename):
         """
-        Save the crates list to a JSON file.
+        Save the crates list to a JSON file, and what is known about the crates to a .meta.json file.
         """
         data = dict((k, list(v)) for k, v in self.crates.items())
         json.dump(data, open(filename, "w"), indent=2)
 
-    def add(self, name, version="latest"):
+        meta = {
+            "origins": self.origins,
+            "summaries": self.summaries,
+            "categories": dict((k, sorted(v)) for k, v in self.categories.items()),
+        }
+        json.dump(meta, open(Path(filename).with_suffix(".meta.json"), "w"), indent=2)
+
+    def add(self, name, version="latest", origin=None):
         """
         Add a crate version to the list of crates.
+        The origin (top, cookbook, curated, command, hidden) is recorded for the explicitly requested crates.
         """
+        key = TopCrates._normalize_name(name)
+        if self.spellings.setdefault(key, name) != name and self.verbose:
+            warn(f"{name} and {self.spellings[key]} are the same crate")
+        name = key
+        if name in self.aliases:
+            warn(f"{name} was renamed to {self.aliases[name]}")
+            name = self.aliases[name]
+        if origin and (name not in self.origins or origin != "hidden" and self.origins[name] == "top"):
+            # an explicit request prevails over the top crates, a hidden one does not hide a requested crate
+            self.origins[name] = origin
+        if origin != "top" and self.excluded.get(name) == "not in the allow list":
+            # an explicit request or a dependency prevails over the allow list
+            del self.excluded[name]
         if any(e.match(name) for e in self.exclusions):
+            self.exclude(name, "excluded by configuration")
+            return
+        if name in self.nightly_only:
+            self.exclude(name, "requires a nightly toolchain")
+            return
+        # only the requests of the top crates are filtered, not their dependencies
+        denied = origin == "top" and self.origins.get(name) == "top" and not any(e.match(name) for e in self.allow)
+        if self.conf_deny_by_default and denied:
+            self.exclude(name, "not in the allow list")
             return
         self.crates[name].add(version)
 
+    def exclude(self, name, reason):
+        """
+        Record why a crate is excluded. Only the first reason is kept.
+        """
+        if name not in self.excluded:
+            self.excluded[name] = reason
+            if GITHUB_ANNOTATIONS:
+                warn(f"excluded: {name} ({reason})")
+            else:
+                print(f"excluded: {name} ({reason})")
+
     def top_crates(self):
         """
         Download the top crates from the [Rust registry](https://crates.io/).
         """
+        plan = [(self.conf_top_crates, "")]
+        for category in self.conf_categories:
+            plan.extend((count, name) for name, count in category.items())
 
-        def _get_top(count, category=""):
-            """
-            Use the crates.io API to fetch crates per download count.
-            """
-            if category:
-                category = f"&category={category}"
-
-            per_page = 100
-            page = 1
-
-            while count > 0:
-                url = f"https://crates.io/api/v1/crates?page={page}&per_page={min(count,per_page)}&sort=downloads{category}"
-                data = requests.get(url).json()
+        self.fetch_plan(plan)
 
-                if self.verbose:
-                    print(url, len(data["crates"]))
+    def fetch_plan(self, plan):
+        """
+        Fetch the most downloaded crates for each (count, category) of the plan. An empty category means all crates.
+        """
+        for count, category in plan:
+            self._get_top(count, category)
 
-                for crate in data["crates"]:
-                    if crate["max_stable_version"]:
-                        self.add(crate["name"], crate["max_stable_version"])
-                    self.add(crate["name"], crate["max_version"])
+    def _get_top(self, count, category=""):
+        """
+        Add the most downloaded crates given by the source.
+        """
+        for crate in self.source.fetch(count, category, self.verbose):
+            self._summarize(crate)
+            name = TopCrates._crate_name(crate)
+            if category:
+                self.categories[TopCrates._normalize_name(name)].add(category)
+            if crate.get("max_stable_version"):
+                self.add(name, crate["max_stable_version"], "top")
+            self.add(name, crate.get("max_version") or "latest", "top")
 
-                page += 1
-                count -= per_page
+    def top_reverse_dependencies(self, cache_file="reverse_dependencies.json"):
+        """
+        Add the crates with the most reverse dependencies, i.e. the most depended upon by the latest versions of all crates.
+        Counts are computed from the crates.io index and cached for its current commit.
+        """
+        if not self.conf_top_reverse_dependencies:
+            return
 
-        _get_top(self.conf_top_crates)
+        head = git_cmd(["rev-parse", "HEAD"], cwd=self.index_dir, capture_output=True, text=True).stdout.strip()
+        cache = json.load(open(cache_file)) if Path(cache_file).is_file() else {}
 
-        for category in self.conf_categories:
-            for name, count in category.items():
-                _get_top(count, name)
+        if cache.get("head") != head or not head:
+            print("Count reverse dependencies")
+            counts = defaultdict(int)
+            for info_file in self.index_dir.glob("**/*"):
+                if ".git" in info_file.parts or not info_file.is_file() or info_file.name == "config.json":
+                    continue
+                lines = info_file.read_text().splitlines()
+                if not lines:
+                    continue
+                latest = json.loads(lines[-1])
+                for dep in set(dep.get("package", dep["name"]) for dep in latest["deps"] if dep["kind"] == "normal"):
+                    counts[TopCrates._normalize_name(dep)] += 1
+            cache = {"head": head, "counts": counts}
+            if self.save_caches:
+                json.dump(cache, open(cache_file, "w"), indent=2)
+
+        ranking = sorted(cache["counts"].items(), key=lambda item: (-item[1], item[0]))
+        for name, count in ranking[: self.conf_top_reverse_dependencies]:
+            if self.verbose:
+                print(f"{name} has {count} reverse dependencies")
+            self.add(name, origin="top")
 
     def cookbook(self):
         """
         Add crates from the [Rust Cookbook](https://rust-lang-nursery.github.io/rust-cookbook/intro.html).
         """
         if self.conf_cookbook:
-            r = requests.get("https://raw.githubusercontent.com/rust-lang-nursery/rust-cookbook/master/Cargo.toml")
-            d = tomli.loads(r.text)
+            url = "https://raw.githubusercontent.com/rust-lang-nursery/rust-cookbook/master/Cargo.toml"
+
+            # conditional request: reuse the cached manifest if it has not changed
+            cache_file = Path("cookbook.json")
+            cache = json.load(open(cache_file)) if cache_file.is_file() else {}
+            headers = {}
+            if cache.get("etag"):
+                headers["If-None-Match"] = cache["etag"]
+            if cache.get("last-modified"):
+                headers["If-Modified-Since"] = cache["last-modified"]
+
+            r = self.http.get(url, headers=headers)
+            if r.status_code == 200:
+                text = r.text
+                cache = {
+                    "etag": r.headers.get("etag"),
+                    "last-modified": r.headers.get("last-modified"),
+                    "text": text,
+                }
+                if self.save_caches:
+                    json.dump(cache, open(cache_file, "w"), indent=2)
+            elif "text" in cache:
+                if r.status_code != 304:
+                    warn(f"{url} returned HTTP {r.status_code}, using the cached manifest")
+                elif self.verbose:
+                    print(f"{url} not modified")
+                text = cache["text"]
+            else:
+                fail(EXIT_NETWORK, f"{url} returned HTTP {r.status_code}")
+
+            d = tomli.loads(text)
             for name in d["dependencies"].keys():
-                self.add(name)
+                self.add(name, origin="cookbook")
 
-    def curated(self):
+    def curated(self, additions=True, commands=True):
         """
         Add curated crates and commands.
         """
-        for k in self.conf_additions:
-            self.add(k)
-        for k in self.conf_commands:
-            self.add(k)
+        if additions:
+            for k in self.conf_additions:
+                self.add(k, origin="curated")
+        if commands:
+            for k in self.conf_commands:
+                self.add(k, origin="command")
+        for k in self.conf_hidden_additions:
+            self.add(k, origin="hidden")
+
+    def fetch_categories(self, cache_file="categories.json"):
+        """
+        Get the crates.io categories of the crates that were not found by category.
+        The categories of the top crates are cached since it needs one request per crate.
+        """
+        cache = json.load(open(cache_file)) if Path(cache_file).is_file() else {}
+
+        for name in self.crates.keys():
+            if self.categories.get(name):
+                continue
+            top = self.origins.get(name, "top") == "top"
+            if top and name in cache:
+                self.categories[name].update(cache[name])
+                continue
+            r = self.http.get(f"{self.conf_api_url}/api/v1/crates/{name}")
+            if r.status_code != 200:
+                warn(f"cannot get categories of {name}")
+                continue
+            crate = r.json()["crate"]
+            self.categories[name].update(crate.get("categories") or [])
+            if top:
+                cache[name] = sorted(self.categories[name])
+                if self.save_caches:
+                    json.dump(cache, open(cache_file, "w"), indent=2)
+            else:
+                self._summarize(crate)
+
+    def exclude_owners(self, cache_file="owners.json"):
+        """
+        Drop the listed crates owned by a user or a team of `excluded-owners`. Owners are cached since it needs one request per crate.
+        """
+        if not self.conf_excluded_owners:
+            return
+
+        cache = json.load(open(cache_file)) if Path(cache_file).is_file() else {}
+
+        for name in list(self.crates.keys()):
+            if name not in cache:
+                url = f"{self.conf_api_url}/api/v1/crates/{name}/owners"
+                r = self.http.get(url)
+                if r.status_code != 200:
+                    warn(f"cannot get owners of {name}")
+                    continue
+                cache[name] = [owner["login"] for owner in r.json()["users"]]
+                if self.save_caches:
+                    json.dump(cache, open(cache_file, "w"), indent=2)
+
+            owners = set(cache[name]).intersection(self.conf_excluded_owners)
+            if owners:
+                self.exclude(name, f"owned by {' '.join(sorted(owners))}")
+                del self.crates[name]
+
+    def exclude_unpopular(self):
+        """
+        Drop the listed crates downloaded less than `min-downloads` times.
+        """
+        if not self.conf_min_downloads:
+            return
+
+        for name in list(self.crates.keys()):
+            if self.summaries.get(name, {}).get("downloads") is None:
+                r = self.http.get(f"{self.conf_api_url}/api/v1/crates/{name}")
+                if r.status_code != 200:
+                    warn(f"cannot get download count of {name}")
+                    continue
+                self._summarize(r.json()["crate"])
+
+            downloads = self.summaries[name]["downloads"]
+            if downloads < self.conf_min_downloads:
+                self.exclude(name, f"{downloads} downloads")
+                del self.crates[name]
+
+    def exclude_large(self, cache_file="crate_sizes.json"):
+        """
+        Drop the top crates whose latest version is larger than `max-crate-size` bytes. Other crates are exempt.
+        Sizes are cached by version since it needs one request per crate.
+        """
+        if not self.conf_max_crate_size:
+            return
+
+        cache = json.load(open(cache_file)) if Path(cache_file).is_file() else {}
+
+        for name in list(self.crates.keys()):
+            if self.origins.get(name, "top") != "top":
+                continue
+
+            if self.summaries.get(name, {}).get("max_version") is None:
+                r = self.http.get(f"{self.conf_api_url}/api/v1/crates/{name}")
+                if r.status_code != 200:
+                    warn(f"cannot get the latest version of {name}")
+                    continue
+                self._summarize(r.json()["crate"])
+            version = self.summaries[name]["max_version"]
+
+            key = f"{name} {version}"
+            if key not in cache:
+                r = self.http.get(f"{self.conf_api_url}/api/v1/crates/{name}/{version}")
+                if r.status_code != 200:
+                    warn(f"cannot get the size of {name} {version}")
+                    continue
+                cache[key] = r.json()["version"].get("crate_size")
+                if self.save_caches:
+                    json.dump(cache, open(cache_file, "w"), indent=2)
+
+            size = cache[key]
+            if size is not None and size > self.conf_max_crate_size:
+                self.exclude(name, f"{version} is {size} bytes")
+                del self.crates[name]
+
+    def _summarize(self, crate):
+        """
+        Keep the fields of a crates.io crate summary used for filtering and for crates_info.json.
+        """
+        summary = dict((k, crate.get(k)) for k in TopCrates.SUMMARY_FIELDS)
+        self.summaries[TopCrates._normalize_name(TopCrates._crate_name(crate))] = summary
+
+    @staticmethod
+    def _crate_name(crate):
+        """
+        Return the name of a crate of the crates.io API, which is also its `id`. Fail clearly if the schema changed.
+        """
+        name = crate.get("name") or crate.get("id")
+        if not isinstance(name, str) or not name:
+            fail(EXIT_NETWORK, f"unexpected crates.io response, a crate has neither name nor id: {sorted(crate)}")
+        return name
+
+    @staticmethod
+    def _normalize_name(name):
+        """
+        Normalize a crate name the way crates.io compares them: case-insensitive, `-` and `_` are equivalent.
+        """
+        return name.lower().replace("_", "-")
 
     @staticmethod
     def _prefix_name(name):
         """
         Make the path a crate to conform [registies](https://doc.rust-lang.org/cargo/reference/registries.html) rules.
         """
+        name = name.lower()
         l = len(name)
         if l == 1:
             return f"1/{name}"
@@ -421,13 +1140,96 @@ class TopCrates:
         else:
             return f"{name[:2]}/{name[2:4]}/{name}"
 
-    def resolve_deps(self, max_iterations=20000):
+    @staticmethod
+    def _index_file(name, index_dir="crates.io-index"):
+        """
+        Find the index file of a crate whose name is normalized: try every `-`/`_` spelling.
+        """
+        parts = name.split("-")
+        for seps in itertools.product("-_", repeat=len(parts) - 1):
+            candidate = parts[0] + "".join(sep + part for sep, part in zip(seps, parts[1:]))
+            info_file = Path(index_dir) / TopCrates._prefix_name(candidate)
+            if info_file.is_file():
+                return info_file
+        return None
+
+    @staticmethod
+    def _version_age(data):
+        """
+        Return the age in days of a version from its index entry, or None if the publish time is unknown.
+        """
+        if not data.get("pubtime"):
+            return None
+        return (datetime.now(timezone.utc) - parsedate(data["pubtime"])).days
+
+    def exclude_heavy(self):
+        """
+        Drop the top crates that depend, directly or not, on a crate of `heavy-crates`. Other crates are exempt.
+        """
+        if not self.conf_heavy_crates:
+            return
+
+        heavy = set(TopCrates._normalize_name(name) for name in self.conf_heavy_crates)
+        roots = dict((name, set(versions)) for name, versions in self.crates.items())
+
+        # a first resolution gives the dependency graph, its exclusions are found again by the real one
+        excluded = dict(self.excluded)
+        with contextlib.redirect_stdout(io.StringIO()):
+            self.resolve_deps(output=None, checks=False)
+        self.crates = defaultdict(set, roots)
+        self.excluded = excluded
+
+        for name in roots:
+            if self.origins.get(name, "top") != "top":
+                continue
+            closure, todo = set(), list(self.graph.get(name, ()))
+            while todo:
+                dep = todo.pop()
+                if dep not in closure:
+                    closure.add(dep)
+                    todo.extend(self.graph.get(dep, ()))
+            found = closure & heavy
+            if found:
+                self.exclude(name, f"depends on {' '.join(sorted(found))}")
+                del self.crates[name]
+
+    def _accept_latest(self, crate, version, data):
+        """
+        Apply the filters to the latest version of a requested crate, or to the versions of a top crate.
+        Return False if the crate is excluded.
+        """
+        age = TopCrates._version_age(data)
+        if age is not None and self.conf_max_version_age and age > self.conf_max_version_age:
+            self.exclude(crate, f"{version} is {age} days old")
+            return False
+        if age is not None and age > self.conf_version_age_warning:
+            warn(f"{crate} {version} is {age} days old")
+
+        # crates from the cookbook, additions and commands are exempt
+        if SemVer(version).parts[0] == 0 and self.origins.get(TopCrates._normalize_name(crate), "top") == "top":
+            if self.conf_zero_major == "exclude":
+                self.exclude(crate, f"{version} is a 0.x version")
+                return False
+            if self.conf_zero_major == "warn":
+                warn(f"{crate} {version} is a 0.x version")
+
+        return True
+
+    def resolve_deps(self, max_iterations=20000, output="selected_crates.json", checks=True):
         """
         Resolve dependencies of all crates, like Cargo does.
+        The selection is written to the output file, if any.
+        Without checks, `exclude-edges` and `min-crates` are not enforced, e.g. for a first resolution.
         """
         print(f"Analyze {len(self.crates)} crates")
 
         seen = set()  # memoize already resolved crates
+        self.graph = defaultdict(set)  # dependencies of crates, by normalized names
+        self.requested = list(self.crates)
+        self.requests = dict((name, set(versions)) for name, versions in self.crates.items())
+        depths = dict((name, 0) for name in self.crates)  # shortest distance from a requested crate
+        filtered = set()  # requested top crates whose versions were filtered
+        too_deep = set()
 
         n = 0
         while len(self.crates) > 0:
@@ -438,10 +1240,13 @@ class TopCrates:
                     print("too many iterations")
                 break
 
-            crate, versions = self.crates.popitem()
+            # breadth-first: crates are resolved in the order they were added
+            crate = next(iter(self.crates))
+            versions = self.crates.pop(crate)
+            depth = depths.get(crate, 0)
 
             if any(e.match(crate) for e in self.exclusions):
-                print(f"excluded: {name} {versions}")
+                self.exclude(crate, "excluded by configuration")
                 continue
 
             try:
@@ -454,33 +1259,73 @@ class TopCrates:
             if len(versions) == 0:
                 continue
 
-            info_file = Path("crates.io-index") / TopCrates._prefix_name(crate)
-            if not info_file.is_file():
+            info_file = TopCrates._index_file(crate, self.index_dir)
+            if info_file is None:
+                self.exclude(crate, "not found in the index")
+                self.excluded.setdefault(crate, "not found in the index")
                 continue
 
             info = {}
             lines = info_file.read_text().splitlines()
             latest = None
             latest_stable = None
+            settled_stable = None
             for line in lines:
                 data = json.loads(line)
+                # use the canonical name from the index, not the normalized one
+                crate = data["name"]
                 latest = data["vers"]
                 info[latest] = data
                 if not SemVer(latest).prerelease:
                     latest_stable = latest
+                    age = TopCrates._version_age(data)
+                    if age is None or age >= self.conf_version_settle_days:
+                        settled_stable = latest
+
+            key = TopCrates._normalize_name(crate)
+            locked = [v for v in self.locked.get(key, ()) if v in info]
+            top = self.origins.get(key, "top") == "top"
+
+            if top and depth == 0 and not locked and key not in filtered:
+                # the versions given by crates.io for the top crates are filtered like the latest ones
+                filtered.add(key)
+                accepted = True
+                for vers in [v for v in versions if v in info]:
+                    age = TopCrates._version_age(info[vers])
+                    if settled_stable and age is not None and age < self.conf_version_settle_days:
+                        print(f"{crate} {vers} is too recent, using {settled_stable}")
+                        versions.remove(vers)
+                        vers = settled_stable
+                        versions.add(vers)
+                    accepted = accepted and self._accept_latest(crate, vers, info[vers])
+                if not accepted:
+                    continue
 
-            if latest and "latest" in versions:
+            if latest and "latest" in versions and not locked:
                 versions.remove("latest")
-                if latest_stable:
+                if settled_stable and top:
+                    # add the latest stable version of a top crate, published long enough ago
+                    latest_version = settled_stable
+                    if settled_stable != latest_stable:
+                        print(f"{crate} {latest_stable} is too recent, using {settled_stable}")
+                elif latest_stable:
                     # add the latest stable version
-                    versions.add(latest_stable)
+                    latest_version = latest_stable
                 else:
                     # if no stable version, add the latest prerelease
-                    versions.add(latest)
+                    latest_version = latest
+
+                if self._accept_latest(crate, latest_version, info[latest_version]):
+                    versions.add(latest_version)
 
             for vers in versions:
 
-                k = SemVer.find_matching(vers, info)
+                # prefer a version of the lockfile, if it matches
+                candidates = SemVer.sorted(v for v in locked if vers == "latest" or SemVer(v).match(vers))
+                if candidates:
+                    k = info[candidates[-1]]
+                else:
+                    k = SemVer.find_matching(vers, info)
 
                 slug = (crate, k["vers"])
                 if slug in seen:
@@ -503,8 +1348,15 @@ class TopCrates:
 
                     assert dep["kind"] in ["normal", "build", "dev"]  # nosec
 
+                    if self.conf_max_depth and depth + 1 > self.conf_max_depth:
+                        too_deep.add(TopCrates._normalize_name(name))
+                        continue
+
+                    self.graph[TopCrates._normalize_name(crate)].add(TopCrates._normalize_name(name))
+
                     if name not in seen:
                         self.add(name, req)
+                        depths.setdefault(TopCrates._normalize_name(name), depth + 1)
                         if self.verbose:
                             print(f"      adding f{name} {req}")
                     else:
@@ -519,37 +1371,518 @@ class TopCrates:
         for k, v in seen:
             self.selected_crates[k].append(v)
 
+        for name in too_deep - set(TopCrates._normalize_name(k) for k in self.selected_crates):
+            self.exclude(name, f"deeper than max-depth {self.conf_max_depth}")
+
+        for name, versions in sorted(self.locked.items()):
+            selected = set(v for k, v in seen if TopCrates._normalize_name(k) == name)
+            if selected and not versions & selected:
+                warn(f"cannot use locked {name} {' '.join(sorted(versions))}, selected {' '.join(selected)}")
+
+        self.force_versions()
+
+        for a, b in self.conf_exclude_edges if checks else ():
+            a, b = TopCrates._normalize_name(a), TopCrates._normalize_name(b)
+            if b in self.graph.get(a, ()) and b not in self.excluded:
+                fail(EXIT_RESOLUTION, f"{a} depends on {b}, which is forbidden by exclude-edges")
+
         print(f"Found {len(self.selected_crates)} crates and {len(seen)} versions")
-        json.dump(self.selected_crates, open("selected_crates.json", "w"), indent=2)
+        if checks and len(self.selected_crates) < self.conf_min_crates:
+            count = len(self.selected_crates)
+            fail(EXIT_RESOLUTION, f"{count} crates found, expected at least {self.conf_min_crates}")
+        if output:
+            Path(output).write_text(self.selected_json())
+
+    def save_information(self, filename="crates_info.json", header=False, sort="name", format="json"):
+        """
+        Save the selected crate versions with what is known about them.
+        With header, the list is wrapped in an object with summary statistics.
+        With sort="downloads", the most downloaded crates come first and those without a known count last.
+        With format="toml", the crates are an array of tables `[[crates]]`, with the same fields.
+        """
+        core = self.core_crates()
+
+        info = []
+        for name, versions in sorted(self.selected_crates.items()):
+            key = TopCrates._normalize_name(name)
+            if self.origins.get(key) == "hidden":
+                continue
+            index = self._index_versions(name)
+            summary = self.summaries.get(key, {})
+            for version in SemVer.sorted(versions):
+                features = dict(index[version].get("features", {}), **index[version].get("features2", {}))
+                crate = {
+                    "name": name,
+                    "version": version,
+                    "categories": sorted(self.categories.get(key, [])),
+                    "description": summary.get("description"),
+                    "repository": summary.get("repository"),
+                    "downloads": summary.get("downloads"),
+                    "features": sorted(features),
+                    "default_features": features.get("default", []),
+                    "tier": "core" if key in core else "extended",
+                    "kind": "command" if self.origins.get(key) == "command" else "library",
+                }
+                if summary.get("homepage"):
+                    crate["homepage"] = summary["homepage"]
+                crate["documentation"] = summary.get("documentation") or f"https://docs.rs/{name}/{version}"
+                info.append(crate)
+
+        if self.conf_max_duplicate_versions is not None:
+            info = self._cap_duplicates(info)
+
+        if sort == "downloads":
+            # stable sort: the name order is kept for equal counts
+            info.sort(key=lambda crate: (crate["downloads"] is None, -(crate["downloads"] or 0)))
+
+        if header:
+            meta = {
+                "crates": len(self.selected_crates),
+                "versions": len(info),
+                "duplicates": sum(1 for versions in self.selected_crates.values() if len(versions) > 1),
+                "excluded": len(self.excluded),
+                "generated": output_time().isoformat(timespec="seconds"),
+            }
+            info = {"meta": meta, "crates": info}
+
+        if format == "toml":
+            Path(filename).write_text(to_toml(info if header else {"crates": info}))
+        else:
+            json.dump(info, open(filename, "w"), indent=2)
+
+        # a sidecar in the format of sha256sum, to know if the file changed without downloading it
+        digest = hashlib.new(self.conf_checksum, Path(filename).read_bytes()).hexdigest()
+        Path(f"{filename}.{self.conf_checksum}").write_text(f"{digest}  {Path(filename).name}\n")
+
+    def _cap_duplicates(self, info):
+        """
+        Keep at most `max-duplicate-versions` entries that are not the newest version of their crate.
+        The duplicates of the least downloaded crates are dropped first, then the oldest versions.
+        They stay in the index: the crates that depend on them still build.
+        """
+        newest = {}
+        for crate in info:
+            newest[crate["name"]] = crate["version"]  # versions are sorted
+        duplicates = [crate for crate in info if crate["version"] != newest[crate["name"]]]
+
+        excess = len(duplicates) - self.conf_max_duplicate_versions
+        if excess <= 0:
+            return info
+
+        def _order(a, b):
+            return (a["downloads"] or 0) - (b["downloads"] or 0) or SemVer(a["version"]).compare(b["version"])
+
+        dropped = sorted(duplicates, key=cmp_to_key(_order))[:excess]
+        warn("max-duplicate-versions: dropped " + ", ".join(f"{c['name']} {c['version']}" for c in dropped))
+        return [crate for crate in info if not any(crate is c for c in dropped)]
+
+    def explain(self, name):
+        """
+        Return the shortest dependency path from a requested crate to a selected crate, or None.
+        The path only goes through selected crates.
+        """
+        name = TopCrates._normalize_name(name)
+        selected = set(TopCrates._normalize_name(k) for k in self.selected_crates) - set(self.excluded)
+        roots = [root for root in self.requested if root in selected]
+
+        # breadth-first from all the requested crates at once
+        parents = dict((root, None) for root in roots)
+        queue = list(roots)
+        while queue:
+            crate = queue.pop(0)
+            if crate == name:
+                path = []
+                while crate is not None:
+                    path.append(crate)
+                    crate = parents[crate]
+                return path[::-1]
+            for dep in sorted(self.graph.get(crate, ())):
+                if dep not in parents and dep in selected:
+                    parents[dep] = crate
+                    queue.append(dep)
+        return None
+
+    def core_crates(self):
+        """
+        Return the selected crates of the core tier: those listed in `core`, and the `core-top` most downloaded ones.
+        """
+        selected = set(TopCrates._normalize_name(name) for name in self.selected_crates)
+        core = set(TopCrates._normalize_name(name) for name in self.conf_core) & selected
+        if self.conf_core_top:
+            popular = sorted(
+                (k for k in selected if self.summaries.get(k, {}).get("downloads")),
+                key=lambda k: (-self.summaries[k]["downloads"], k),
+            )
+            core.update(popular[: self.conf_core_top])
+        return core
+
+    def _index_versions(self, name):
+        """
+        Return the index entries of a crate, by version.
+        """
+        info_file = TopCrates._index_file(TopCrates._normalize_name(name), self.index_dir)
+        if info_file is None:
+            return {}
+        return dict((data["vers"], data) for data in map(json.loads, info_file.read_text().splitlines()))
+
+    def save_crates_io(self, filename):
+        """
+        Save the selected crates in the shape of the crates.io `/api/v1/crates` response.
+        """
+        crates = []
+        for name, versions in sorted(self.selected_crates.items()):
+            versions = SemVer.sorted(versions)
+            stable = [v for v in versions if not SemVer(v).prerelease]
+            crates.append(
+                {
+                    "id": name,
+                    "name": name,
+                    "max_version": versions[-1],
+                    "max_stable_version": stable[-1] if stable else None,
+                    "downloads": self.summaries.get(TopCrates._normalize_name(name), {}).get("downloads"),
+                }
+            )
+        json.dump({"crates": crates, "meta": {"total": len(crates)}}, open(filename, "w"), indent=2)
+
+    def save_exclusions(self, filename="exclusions.json"):
+        """
+        Save the excluded crates with the reason of their exclusion.
+        """
+        data = [{"name": name, "reason": reason} for name, reason in sorted(self.excluded.items())]
+        json.dump(data, open(filename, "w"), indent=2)
+
+    def save_replacements(self, filename="replacements.json"):
+        """
+        Save the versions replaced because of an advisory, with their replacement.
+        """
+        json.dump(self.replacements, open(filename, "w"), indent=2)
+
+    def save_graph(self, filename):
+        """
+        Save the dependency graph of the selection: for each crate version, its dependencies with the selected version
+        that satisfies them (null if none, e.g. excluded or deeper than max-depth).
+        """
+        by_name = dict((TopCrates._normalize_name(name), versions) for name, versions in self.selected_crates.items())
+
+        graph = {}
+        for name, versions in sorted(self.selected_crates.items()):
+            index = self._index_versions(name)
+            graph[name] = {}
+            for version in SemVer.sorted(versions):
+                deps = []
+                for dep in index[version]["deps"]:
+                    dep_name = dep.get("package", dep["name"])
+                    candidates = by_name.get(TopCrates._normalize_name(dep_name), [])
+                    candidates = SemVer.sorted(v for v in candidates if SemVer(v).match(dep["req"]))
+                    deps.append(
+                        {
+                            "name": dep_name,
+                            "req": dep["req"],
+                            "kind": dep["kind"],
+                            "optional": dep["optional"],
+                            "target": dep.get("target"),
+                            "version": candidates[-1] if candidates else None,
+                        }
+                    )
+                graph[name][version] = deps
+
+        json.dump(graph, open(filename, "w"), indent=2)
+
+    @staticmethod
+    def load_selection(filename="selected_crates.json"):
+        """
+        Load a selection, checking its shape: an object of crate names to lists of exact versions.
+        A stale or hand-edited file fails here with a clear message, not later while writing the index.
+        """
+        try:
+            data = json.load(open(filename))
+        except FileNotFoundError:
+            fail(EXIT_ERROR, f"{filename} not found, run the resolution first")
+        except json.JSONDecodeError as e:
+            fail(EXIT_ERROR, f"{filename} is not valid JSON: {e}")
+
+        if not isinstance(data, dict):
+            fail(EXIT_ERROR, f"{filename} must be an object of crate names to lists of versions")
+        for name, versions in data.items():
+            if not re.match(r"^[A-Za-z0-9_-]+$", name):
+                fail(EXIT_ERROR, f"{filename}: invalid crate name {name!r}")
+            if not isinstance(versions, list) or not versions:
+                fail(EXIT_ERROR, f"{filename}: {name} must have a non-empty list of versions")
+            for version in versions:
+                if not isinstance(version, str) or not SemVer._REGEX.match(version):
+                    fail(EXIT_ERROR, f"{filename}: {name} has an invalid version {version!r}")
+        return data
+
+    def selected_json(self):
+        """
+        Return the selection as JSON, with crates and versions sorted to get a reproducible output.
+        """
+        data = dict((k, SemVer.sorted(v)) for k, v in sorted(self.selected_crates.items()))
+        return json.dumps(data, indent=2) + "\n"
+
+    def save_compressed(self, filename):
+        """
+        Save the selection as gzip-compressed selected_crates.json, with the same content.
+        """
+        with open(filename, "wb") as f:
+            with gzip.GzipFile("selected_crates.json", "wb", fileobj=f, mtime=output_time().timestamp()) as z:
+                z.write(self.selected_json().encode())
+
+    @staticmethod
+    def diff_selections(old, new):
+        """
+        Compare two selections. Return the added, removed and changed crates.
+        """
+        added = sorted(set(new) - set(old))
+        removed = sorted(set(old) - set(new))
+        changed = sorted(k for k in set(old) & set(new) if set(old[k]) != set(new[k]))
+        return added, removed, changed
+
+    def force_versions(self):
+        """
+        Replace the newest selected version of crates listed in `force-versions`.
+        This bypasses the resolution: dependencies of the forced version are not checked.
+        """
+        for name, version in self.conf_force_versions.items():
+            if name not in self.selected_crates:
+                warn(f"cannot force {name} {version}, crate is not selected")
+                continue
+
+            if version not in self._index_versions(name):
+                fail(EXIT_CONFIG, f"cannot force {name} {version}, version not found in the index")
 
-    def make_index(self, index_dir="local-registry/index"):
+            versions = SemVer.sorted(self.selected_crates[name])
+            if self.verbose:
+                print(f"forced: {name} {versions[-1]} -> {version}")
+            versions[-1] = version
+            self.selected_crates[name] = sorted(set(versions))
+
+    def duplicate_majors(self):
+        """
+        Return the crates selected in several semver-incompatible versions, with the crates that require each version.
+        """
+        by_name = dict((TopCrates._normalize_name(name), versions) for name, versions in self.selected_crates.items())
+
+        duplicates = {}
+        for name, versions in sorted(by_name.items()):
+            majors = set(SemVer.compatibility(v) for v in versions)
+            if len(majors) > 1:
+                duplicates[name] = dict((v, set()) for v in SemVer.sorted(versions))
+
+        if not duplicates:
+            return duplicates
+
+        for dependent, versions in self.selected_crates.items():
+            index = self._index_versions(dependent)
+            for version in versions:
+                for dep in index.get(version, {}).get("deps", []):
+                    name = TopCrates._normalize_name(dep.get("package", dep["name"]))
+                    if name not in duplicates:
+                        continue
+                    # the resolver pulls the dev-dependencies in too
+                    dev = " (dev)" if dep["kind"] == "dev" else ""
+                    for v, required_by in duplicates[name].items():
+                        if SemVer(v).match(dep["req"]):
+                            required_by.add(f"{dependent} {version}{dev}")
+
+        return duplicates
+
+    def check_required(self):
+        """
+        Return the explicitly requested crates (cookbook, curated, commands) missing from the selection, with the reason.
+        """
+        requested = set(name for name, origin in self.origins.items() if origin != "top")
+        requested.update(TopCrates._normalize_name(name) for name in self.conf_additions + self.conf_commands)
+        selected = set(TopCrates._normalize_name(name) for name in self.selected_crates)
+
+        missing = []
+        for name in sorted(requested - selected):
+            if name in self.excluded:
+                reason = self.excluded[name]
+            elif TopCrates._index_file(name, self.index_dir) is None:
+                reason = "not found in the index"
+            else:
+                reason = "not resolved"
+            missing.append((name, reason))
+        return missing
+
+    @staticmethod
+    def check_regressions(previous, selected_crates):
+        """
+        Compare the newest selected version of each crate with a previous selection. Return the downgraded crates.
+        """
+        regressions = []
+        for name, versions in sorted(selected_crates.items()):
+            if name not in previous or len(previous[name]) == 0:
+                continue
+            old_version = SemVer.sorted(previous[name])[-1]
+            new_version = SemVer.sorted(versions)[-1]
+            if SemVer(new_version).compare(old_version) < 0:
+                regressions.append((name, old_version, new_version))
+        return regressions
+
+    def yanked_versions(self):
+        """
+        Return the selected versions that are yanked, e.g. since the resolution or in the lockfile.
+        """
+        return [
+            (name, version)
+            for name, versions in sorted(self.selected_crates.items())
+            for version in SemVer.sorted(versions)
+            if self._index_versions(name).get(version, {}).get("yanked")
+        ]
+
+    def missing_versions(self):
+        """
+        Return the selected versions that are no more in the index, e.g. since the resolution.
+        """
+        return [
+            (name, version)
+            for name, versions in sorted(self.selected_crates.items())
+            for version in SemVer.sorted(versions)
+            if version not in self._index_versions(name)
+        ]
+
+    def resolve_yanked(self):
+        """
+        Resolve again the requested crates if selected versions are yanked, without preferring them from the lockfile.
+        The resolver still uses a yanked version when no other one matches a requirement.
+        """
+        yanked = self.yanked_versions()
+        if not yanked:
+            return
+
+        for name, version in yanked:
+            warn(f"{name} {version} has been yanked, resolve again")
+            self.locked[TopCrates._normalize_name(name)].discard(version)
+        self.crates = defaultdict(set, ((name, set(versions)) for name, versions in self.requests.items()))
+        self.resolve_deps(output=None)
+
+        for name, version in self.yanked_versions():
+            warn(f"{name} {version} has been yanked and has no replacement")
+
+    def _requirements(self, name):
+        """
+        Return the requirements of the selected crate versions on a crate.
+        """
+        key = TopCrates._normalize_name(name)
+        requirements = []
+        for parent, versions in self.selected_crates.items():
+            index = self._index_versions(parent)
+            for version in versions:
+                for dep in index.get(version, {}).get("deps", []):
+                    if TopCrates._normalize_name(dep.get("package", dep["name"])) == key:
+                        requirements.append(dep["req"])
+        return requirements
+
+    def _check_deps(self, name, version):
+        """
+        Warn about the dependencies of a version added without resolving them again, that the selection does not satisfy.
+        """
+        for dep in self._index_versions(name)[version]["deps"]:
+            dep_name = dep.get("package", dep["name"])
+            if dep["kind"] == "dev" or dep["optional"]:
+                continue
+            if not any(SemVer(v).match(dep["req"]) for v in self.selected_crates.get(dep_name, [])):
+                warn(f"{name} {version} requires {dep_name} {dep['req']}, resolve again")
+
+    @staticmethod
+    def index_paths(selected_crates):
+        """
+        Return the relative paths of the index files of a selection, e.g. to build a rsync filter or a CDN purge list.
+        """
+        return sorted(set(TopCrates._prefix_name(name) for name in selected_crates))
+
+    def make_index(self, index_dir="local-registry/index", keep_all_versions=False, format="files"):
         """
         Build the crates index with the required arborescence: <prefix>/<crate>. Each line of a crate file describes a version.
+        With keep_all_versions, all the versions of the selected crates are kept, not only the selected ones.
+        With format="sqlite", the lines are written instead into the table `crates (name, version, json)` of <index_dir>.sqlite.
         """
         if self.selected_crates is None:
-            self.selected_crates = json.load(open("selected_crates.json"))
+            self.selected_crates = TopCrates.load_selection()
 
-        # remove the whole index, it will be recreated
-        for p in Path(index_dir).glob("*"):
-            if len(p.name) <= 2 and p.is_dir():
-                # skip .git, config.json, etc.
-                shutil.rmtree(p, ignore_errors=True)
+        written = set()
+        skipped = 0
+
+        missing = []
+        entries = []  # (name, lines) of the crates, checked before anything is written
+        rows = []
 
         for name, versions in self.selected_crates.items():
 
-            data = Path(f"crates.io-index/{TopCrates._prefix_name(name)}")
+            data = self.index_dir / TopCrates._prefix_name(name)
+            lines = data.read_text().splitlines() if data.is_file() else []
 
             versions = set(versions)
             new_data = []
-            for line in data.read_text().splitlines():
+            found = set()
+            for line in lines:
                 v = json.loads(line)
-                if v["vers"] in versions:
+                if keep_all_versions or v["vers"] in versions:
                     new_data.append(line)
+                    found.add(v["vers"])
+
+            missing.extend(f"{name} {version}" for version in SemVer.sorted(versions - found))
+
+            if new_data:
+                entries.append((name, new_data))
+
+        if missing:
+            fail(EXIT_RESOLUTION, f"not in the index, resolve again: {', '.join(missing)}")
+
+        for name, new_data in entries:
+            if format == "sqlite":
+                rows.extend((json.loads(line)["name"], json.loads(line)["vers"], line) for line in new_data)
+                continue
 
             f = Path(index_dir) / TopCrates._prefix_name(name)
-            f.parent.mkdir(exist_ok=True, parents=True)
+            written.add(f)
             new_data.append("")
-            f.write_text("\n".join(new_data))
+            new_data = "\n".join(new_data)
+
+            # leave unchanged files untouched, not to bump their mtime
+            if f.is_file() and f.read_text() == new_data:
+                skipped += 1
+                continue
+            f.parent.mkdir(exist_ok=True, parents=True)
+            f.write_text(new_data)
+
+        if format == "sqlite":
+            self._write_sqlite(Path(f"{index_dir}.sqlite"), rows)
+            return
+
+        # remove the crates that are no more selected
+        removed = 0
+        for p in Path(index_dir).glob("*"):
+            if len(p.name) <= 2 and p.is_dir():
+                # skip .git, config.json, etc.
+                for f in sorted(p.rglob("*"), reverse=True):
+                    if f.is_file() and f not in written:
+                        f.unlink()
+                        removed += 1
+                    elif f.is_dir() and not any(f.iterdir()):
+                        f.rmdir()
+                if not any(p.iterdir()):
+                    p.rmdir()
+
+        print(f"index: {len(written) - skipped} files written, {skipped} unchanged, {removed} removed")
+
+    @staticmethod
+    def _write_sqlite(filename, rows):
+        """
+        Write the index lines into a new SQLite database, replacing the previous one at once.
+        """
+        filename.parent.mkdir(exist_ok=True, parents=True)
+        tmp = filename.with_name(filename.name + ".part")
+        tmp.unlink(missing_ok=True)
+        with contextlib.closing(sqlite3.connect(tmp)) as db:
+            db.execute("CREATE TABLE crates (name TEXT NOT NULL, version TEXT NOT NULL, json TEXT NOT NULL)")
+            db.execute("CREATE UNIQUE INDEX crates_name_version ON crates (name, version)")
+            db.executemany("INSERT INTO crates VALUES (?, ?, ?)", rows)
+            db.commit()
+        tmp.replace(filename)
+        print(f"index: {len(rows)} versions written to {filename}")
 
     def download_crates(self, crates_dir="local-registry", purge=False):
         """
@@ -565,7 +1898,7 @@ class TopCrates:
             for version in versions:
                 crate_file = f"{name}-{version}.crate"
                 if crate_file not in existing:
-                    downloads.append((name, version))
+                    downloads.append((name, version, self._index_versions(name).get(version, {}).get("cksum", "")))
                 else:
                     existing.discard(crate_file)
 
@@ -584,59 +1917,144 @@ class TopCrates:
             print("No new crates to download")
             return
 
+        self._download_all(downloads, crates_dir)
+
+    def prefetch_crates(self, lockfile, crates_dir="local-registry"):
+        """
+        Start downloading the crates of a Cargo.lock in the background, e.g. during the index update.
+        download_crates() will find them already there. Return the thread to join.
+        """
+        crates_dir = Path(crates_dir)
+        crates_dir.mkdir(exist_ok=True, parents=True)
+
+        downloads = []
+        for package in tomli.load(open(lockfile, "rb")).get("package", []):
+            if package.get("source", "").startswith("registry+"):
+                if not (crates_dir / f"{package['name']}-{package['version']}.crate").exists():
+                    downloads.append((package["name"], package["version"], package.get("checksum", "")))
+
+        thread = threading.Thread(target=self._download_all, args=(downloads, crates_dir), daemon=True)
+        if downloads:
+            print(f"Prefetching {len(downloads)} crate{'' if len(downloads) < 2 else 's'} of {lockfile}")
+            thread.start()
+        return thread
+
+    def _download_all(self, downloads, crates_dir):
+        """
+        Download crates with a pool of processes.
+        """
         num = multiprocessing.Value("i", 0)
         total = len(downloads)
 
+        # the downloads are counted with the API requests, against what is left of max-bytes
+        downloaded = multiprocessing.Value("q", 0)
+        with self.http.lock:
+            budget = max(self.http.max_bytes - self.http.bytes, 1) if self.http.max_bytes else 0
+
         # multiprocessing download with 16 workers
-        pool = Pool(16, initializer=TopCrates._init_mp_session, initargs=(num, total))
-        download_func = partial(TopCrates._download_crate, crates_dir=crates_dir)
+        pool = Pool(16, initializer=TopCrates._init_mp_session, initargs=(num, total, downloaded, budget))
+        download_func = partial(TopCrates._download_crate, crates_dir=crates_dir, download_url=self.conf_download_url)
         pool.map(download_func, downloads)
         pool.close()
         pool.join()
 
+        with self.http.lock:
+            self.http.requests += num.value
+            self.http.bytes += downloaded.value
+        if budget and downloaded.value >= budget and num.value < total:
+            fail(
+                EXIT_NETWORK,
+                f"too many bytes downloaded ({self.http.bytes}), {total - num.value} crates not downloaded",
+            )
+
         print(f"Downloaded {total} new crate{'' if total < 2 else 's'}", " " * 80)
 
     @staticmethod
-    def _init_mp_session(counter, total):
+    def _init_mp_session(counter, total, downloaded, budget):
         """
         Initialize a multiprocessing session.
-        Set up a new Requests session for each process and set the shared counter.
+        Set up a new Requests session for each process and set the shared counters.
         """
         get_context().session = requests.Session()
         get_context().counter = counter
         get_context().total = total
+        get_context().downloaded = downloaded
+        get_context().budget = budget
+
+    @staticmethod
+    def _crate_url(download_url, name, version, checksum=""):
+        """
+        Return the download URL of a crate version, with the markers of the `dl` field of a registry config.json.
+        Raise ValueError for an unknown marker.
+        """
+        if not re.search(r"\{[\w-]+\}", download_url):
+            # as Cargo does, without any marker
+            return f"{download_url}/{name}/{version}/download"
+        prefix = str(len(name)) if len(name) < 3 else f"3/{name[0]}" if len(name) == 3 else f"{name[:2]}/{name[2:4]}"
+        markers = {
+            "crate": name,
+            "version": version,
+            "prefix": prefix,
+            "lowerprefix": prefix.lower(),
+            "sha256-checksum": checksum,
+        }
+
+        def _marker(m):
+            if m.group(1) not in markers:
+                raise ValueError(f"unknown marker {m.group(0)} in {download_url}")
+            return markers[m.group(1)]
+
+        return re.sub(r"\{([\w-]+)\}", _marker, download_url)
 
     @staticmethod
-    def _download_crate(name_version, crates_dir):
+    def _download_crate(download, crates_dir, download_url):
         """
         Download a crate in a multiprocessing session. Requests session is reused and shared counter is updated.
         """
-        name, version = name_version
+        name, version, checksum = download
         context = get_context()
         session = context.session
         counter = context.counter
 
+        if context.budget and context.downloaded.value >= context.budget:
+            return
+
         with counter.get_lock():
             counter.value += 1
 
-        url = f" https://static.crates.io/crates/{name}/{name}-{version}.crate"
+        url = TopCrates._crate_url(download_url, name, version, checksum)
         dest_file = crates_dir / f"{name}-{version}.crate"
 
         print(f"{counter.value:6}/{context.total}  {url.ljust(100)[-100:]}\r", end="")
 
         r = session.get(url)
-        dest_file.write_bytes(r.content)
+        with context.downloaded.get_lock():
+            context.downloaded.value += len(r.content)
+        if r.status_code != 200:
+            print()
+            warn(f"cannot download {name} {version}: HTTP {r.status_code}")
+            return
+
+        # write to a temporary file first: an interrupted download must not leave a truncated .crate
+        part_file = dest_file.with_suffix(".part")
+        part_file.write_bytes(r.content)
         if "last-modified" in r.headers:
             url_date = parsedate(r.headers["last-modified"])
             mtime = round(url_date.timestamp() * 1_000_000_000)
-            os.utime(dest_file, ns=(mtime, mtime))
+            os.utime(part_file, ns=(mtime, mtime))
+        part_file.replace(dest_file)
 
-    def audit(self):
+    def audit(self, advisory_db="advisory-db"):
+        """
+        Print the selected versions affected by a [RustSec](https://rustsec.org/) advisory. Return them by crate.
+        """
         if self.selected_crates is None:
-            self.selected_crates = json.load(open("selected_crates.json"))
+            self.selected_crates = TopCrates.load_selection()
+
+        findings = {}
 
         for name, versions in self.selected_crates.items():
-            d = Path("advisory-db") / "crates" / name
+            d = Path(advisory_db) / "crates" / name
             if d.is_dir():
 
                 ko = {}
@@ -667,12 +2085,109 @@ class TopCrates:
                                 }
 
                 if len(ko) != 0:
+                    findings[name] = ko
                     print(f"{name}")
                     for pb in ko.values():
                         print(f"   versions: {' '.join(pb['versions'])}")
                         print(f"   id:       {' '.join(pb['id'])}  {pb['url']}")
                         print(f"   safe: {pb['v']}")
 
+        return findings
+
+    def compile_check(self, sample=0, target=None):
+        """
+        Check that each selected crate version builds alone with `cargo check`, as the dependency of an empty package.
+        With sample, only this number of versions picked at random are checked.
+        A version that fails is dropped if no other selected crate depends on its crate. Return the failures.
+        """
+        checks = [
+            (name, version)
+            for name, versions in sorted(self.selected_crates.items())
+            if self.origins.get(TopCrates._normalize_name(name)) != "hidden"
+            for version in SemVer.sorted(versions)
+        ]
+        if sample and sample < len(checks):
+            checks = sorted(random.sample(checks, sample))
+
+        failures = []
+        with tempfile.TemporaryDirectory() as tmp:
+            # one target directory: the common dependencies are built once
+            env = dict(os.environ, CARGO_TARGET_DIR=str(Path(tmp) / "target"))
+            command = ["cargo", "check", "--quiet"] + (["--target", target] if target else [])
+
+            for n, (name, version) in enumerate(checks, 1):
+                package = Path(tmp) / f"check-{n}"
+                (package / "src").mkdir(parents=True)
+                (package / "src" / "lib.rs").write_text("")
+                (package / "Cargo.toml").write_text(
+                    '[package]\nname = "compile-check"\nversion = "0.0.0"\nedition = "2021"\n\n'
+                    f'[dependencies]\n{json.dumps(name)} = "={version}"\n'
+                )
+                r = subprocess.run(command, cwd=package, env=env, capture_output=True, text=True)  # nosec
+                print(f"{n:4}/{len(checks)}  {name} {version}: {'ok' if r.returncode == 0 else 'FAILED'}")
+                if r.returncode != 0:
+                    failures.append((name, version))
+                    if self.verbose:
+                        print(r.stderr)
+
+        dependents = set(itertools.chain.from_iterable(self.graph.values()))
+        for name, version in failures:
+            if TopCrates._normalize_name(name) in dependents:
+                warn(f"{name} {version} fails to compile, but other crates depend on it")
+                continue
+            self.exclude(TopCrates._normalize_name(name), f"{version} fails to compile")
+            self.selected_crates[name].remove(version)
+            if not self.selected_crates[name]:
+                del self.selected_crates[name]
+
+        return failures
+
+    def audit_fix(self, advisory_db="advisory-db"):
+        """
+        Replace the versions affected by an advisory by the nearest patched version, or drop them if there is none.
+        A version that other selected crates depend on is kept, unless the patched version satisfies their requirements.
+        """
+        for name, ko in self.audit(advisory_db).items():
+            index = self._index_versions(name)
+            versions = self.selected_crates[name]
+            requirements = self._requirements(name)
+
+            for pb in ko.values():
+                patched = pb["v"].get("patched", [])
+                for version in pb["versions"]:
+                    if version not in versions:
+                        continue
+
+                    fixed = [
+                        v
+                        for v, data in index.items()
+                        if not data["yanked"]
+                        and not SemVer(v).prerelease
+                        and SemVer(v).compare(version) > 0
+                        and any(SemVer(v).match(p) for p in patched)
+                    ]
+                    fixed = SemVer.sorted(fixed)[0] if fixed else None
+
+                    reqs = [req for req in requirements if SemVer(version).match(req)]
+                    if reqs and (fixed is None or not all(SemVer(fixed).match(req) for req in reqs)):
+                        warn(f"{name} {version} is affected by {pb['id'][0]}, but other crates depend on it")
+                        continue
+                    versions.remove(version)
+
+                    if fixed:
+                        warn(f"{name} {version} replaced by {fixed} ({pb['id'][0]})")
+                        self.replacements.append(
+                            {"name": name, "version": version, "replacement": fixed, "advisory": pb["id"][0]}
+                        )
+                        if fixed not in versions:
+                            versions.append(fixed)
+                            self._check_deps(name, fixed)
+                    else:
+                        self.exclude(name, f"{version} is affected by {pb['id'][0]}")
+
+            if not versions:
+                del self.selected_crates[name]
+
 
 def git_cmd(cmd, *args, **kwargs):
     """
@@ -681,6 +2196,180 @@ def git_cmd(cmd, *args, **kwargs):
     return subprocess.run(["git"] + cmd, *args, **kwargs)  # nosec
 
 
+def init_git_registry(index_dir, download_url, api_url):
+    """
+    Create the git repository of a registry index, with the config.json that points to the download server.
+    """
+    Path(index_dir).mkdir(exist_ok=True, parents=True)
+    git_cmd(["init", "-b", "master"], cwd=index_dir)
+    config = {"dl": download_url, "api": api_url}
+    (Path(index_dir) / "config.json").write_text(json.dumps(config, indent=2) + "\n")
+
+
+def fetch_advisory_db():
+    """
+    Clone the RustSec advisory database, if not already done.
+    """
+    if not Path("advisory-db").is_dir():
+        print("Downloading advisory-db")
+        git_cmd(["clone", "-b", "main", "https://github.com/rustsec/advisory-db"])
+    else:
+        # git_cmd(["fetch", "--all"], cwd="advisory-db")
+        # git_cmd(["reset", "--hard", "origin/master"], cwd="advisory-db")
+        pass
+
+
+def merge_indexes(output_dir, index_dirs):
+    """
+    Merge several local registry indexes into output_dir: the union of their crates and versions.
+    Return the number of versions described differently by two indexes, the first description is kept.
+    """
+    merged = defaultdict(dict)
+    conflicts = 0
+
+    for index_dir in map(Path, index_dirs):
+        for f in sorted(index_dir.rglob("*")):
+            prefix = f.relative_to(index_dir).as_posix()
+            if not f.is_file() or prefix != TopCrates._prefix_name(f.name):
+                # skip .git, config.json, etc.
+                continue
+            for line in f.read_text().splitlines():
+                data = json.loads(line)
+                known = merged[prefix].get(data["vers"])
+                if known is None:
+                    merged[prefix][data["vers"]] = line
+                elif json.loads(known) != data:
+                    warn(f"{data['name']} {data['vers']} differs in {index_dir}")
+                    conflicts += 1
+
+    output_dir = Path(output_dir)
+    for prefix, lines in merged.items():
+        f = output_dir / prefix
+        f.parent.mkdir(exist_ok=True, parents=True)
+        f.write_text("\n".join(lines[v] for v in SemVer.sorted(lines)) + "\n")
+
+    config = Path(index_dirs[0]) / "config.json"
+    if config.is_file() and not (output_dir / "config.json").exists():
+        shutil.copy(config, output_dir / "config.json")
+
+    print(f"{len(merged)} crates merged from {len(index_dirs)} indexes")
+    return conflicts
+
+
+def index_changes(index_dir, old_ref, new_ref):
+    """
+    Return the crates whose file changed in a git registry index between two refs, with their versions at each ref.
+    """
+    diff = git_cmd(["diff", "--name-only", old_ref, new_ref], cwd=index_dir, capture_output=True, text=True, check=True)
+    # skip config.json, etc.
+    paths = [path for path in diff.stdout.splitlines() if path == TopCrates._prefix_name(Path(path).name)]
+
+    selections = []
+    for ref in (old_ref, new_ref):
+        selection = {}
+        for path in paths:
+            blob = git_cmd(["show", f"{ref}:{path}"], cwd=index_dir, capture_output=True, text=True)
+            if blob.returncode != 0:
+                # the crate file does not exist at this ref
+                continue
+            for line in blob.stdout.splitlines():
+                data = json.loads(line)
+                selection.setdefault(data["name"], []).append(data["vers"])
+        selections.append(selection)
+    return selections
+
+
+def changes_markdown(old, new):
+    """
+    Describe the differences between two selections as a Markdown list, e.g. for release notes.
+    """
+    added, removed, changed = TopCrates.diff_selections(old, new)
+    lines = []
+    for title, names in (("Added", added), ("Removed", removed), ("Updated", changed)):
+        if names:
+            lines.append(f"### {title}\n")
+            for name in names:
+                if name in old and name in new:
+                    versions = f"{' '.join(SemVer.sorted(old[name]))} → {' '.join(SemVer.sorted(new[name]))}"
+                else:
+                    versions = " ".join(SemVer.sorted(new.get(name) or old[name]))
+                lines.append(f"- `{name}` {versions}")
+            lines.append("")
+    return "\n".join(lines) or "No changes\n"
+
+
+def check_index(index_dir, index_url):
+    """
+    Make sure the crates.io index is a usable git repository, e.g. after a clone killed midway. Clone it again if not.
+    """
+    index_dir = Path(index_dir)
+    if (index_dir / ".git").exists():
+        if git_cmd(["rev-parse", "--verify", "-q", "HEAD"], cwd=index_dir, capture_output=True).returncode == 0:
+            return
+        warn(f"{index_dir} is broken, clone it again")
+        shutil.rmtree(index_dir)
+    elif index_dir.is_dir() and any(index_dir.iterdir()):
+        return
+
+    if index_dir.is_dir():
+        index_dir.rmdir()
+    git_cmd(["clone", index_url, str(index_dir)])
+
+
+def update_index(index_dir, retries=3, delay=10):
+    """
+    Update the crates.io index. Transient failures are retried, then a locked index is told apart from a broken one.
+    """
+    for cmd in (["fetch", "--all"], ["reset", "--hard", "origin/master"]):
+        for attempt in range(retries):
+            r = git_cmd(cmd, cwd=index_dir, capture_output=True, text=True)
+            if r.returncode == 0:
+                break
+            warn(f"git {cmd[0]} failed (attempt {attempt + 1}/{retries}): {r.stderr.strip()}")
+            if attempt + 1 < retries:
+                time.sleep(delay)
+        else:
+            if ".lock" in r.stderr:
+                fail(EXIT_IO, f"{index_dir} is locked by another git process, wait for it to finish and retry")
+            fail(EXIT_IO, f"cannot update {index_dir}, it may be corrupted: remove it and clone it again")
+
+
+def make_registry(a, args):
+    """
+    Write the index of the selected crates, and download them unless a Git registry is made.
+    """
+    if args.git_registry:
+        if not Path("top-crates-index/.git").is_dir():
+            init_git_registry("top-crates-index", a.conf_download_url, a.conf_api_url)
+
+        remote = git_cmd(["remote", "get-url", "origin"], cwd="top-crates-index", capture_output=True)
+        has_remote = remote.returncode == 0
+
+        if args.commit and has_remote:
+            git_cmd(["clean", "-ffdx"], cwd="top-crates-index")
+            git_cmd(["reset", "--hard", "origin/master"], cwd="top-crates-index")
+
+        a.make_index("top-crates-index", args.keep_all_versions)
+        git_cmd(["status", "-s"], cwd="top-crates-index")
+
+        if args.commit:
+            commit = ["commit", "-m", args.commit_message]
+            if args.commit_author:
+                commit.append(f"--author={args.commit_author}")
+            env = None
+            if os.environ.get("SOURCE_DATE_EPOCH"):
+                date = f"@{os.environ['SOURCE_DATE_EPOCH']} +0000"
+                env = dict(os.environ, GIT_AUTHOR_DATE=date, GIT_COMMITTER_DATE=date)
+            git_cmd(["add", "."], cwd="top-crates-index")
+            git_cmd(commit, cwd="top-crates-index", env=env)
+            if has_remote:
+                git_cmd(["push", "origin", "master"], cwd="top-crates-index")
+
+    else:
+        a.make_index(keep_all_versions=args.keep_all_versions, format=args.index_format)
+        a.download_crates(purge=args.purge)
+
+
 def main():
     """
     Main function.
@@ -689,21 +2378,170 @@ def main():
     parser = argparse.ArgumentParser(description="Create an index for the top crates")
 
     parser.add_argument("-v", "--verbose", action="store_true", help="Verbose output")
+    parser.add_argument("--config", default="top-crates.toml", help="Configuration file (default: %(default)s)")
+    parser.add_argument("--index", help="crates.io index directory (default: from the configuration)")
     parser.add_argument("-d", "--download", action="store_true", help="Force build the list of crates")
+    parser.add_argument("--no-cookbook", action="store_true", help="Do not add crates from the Rust Cookbook")
+    parser.add_argument("--no-curated", action="store_true", help="Do not add the curated crates")
+    parser.add_argument("--no-commands", action="store_true", help="Do not add the command line tools")
+    parser.add_argument("--top-only", action="store_true", help="Only use the top crates")
+    parser.add_argument(
+        "--phase",
+        choices=["list", "resolve", "registry"],
+        help="Only build crates.json (list), selected_crates.json (resolve) or the registry from selected_crates.json",
+    )
     parser.add_argument("-u", "--update", action="store_true", help="Fetch the upstream")
     parser.add_argument("-p", "--purge", action="store_true", help="Remove encumbered crates")
     parser.add_argument("-c", "--commit", action="store_true", help="Commit the new index")
     parser.add_argument("-g", "--git-registry", action="store_true", help="Make a Git registry")
+    parser.add_argument(
+        "--commit-message", default="Update top crates index", help="Commit message of the Git registry"
+    )
+    parser.add_argument("--commit-author", metavar="AUTHOR", help="Commit author of the Git registry (Name <email>)")
     parser.add_argument("-a", "--audit", action="store_true", help="Audit")
+    parser.add_argument(
+        "--audit-fix", action="store_true", help="Replace or drop the versions with an advisory, see replacements.json"
+    )
+    parser.add_argument("--top-file", metavar="FILE", help="Read the top crates from a file instead of crates.io")
+    parser.add_argument(
+        "--export-crates-io", metavar="FILE", help="Also write the selection in the crates.io API format"
+    )
+    parser.add_argument("--graph", metavar="FILE", help="Also write the dependency graph of the selection")
+    parser.add_argument("--info-header", action="store_true", help="Add summary statistics to crates_info.json")
+    parser.add_argument(
+        "--format", choices=["json", "toml"], default="json", help="Format of crates_info (default: %(default)s)"
+    )
+    parser.add_argument(
+        "--sort", choices=["name", "downloads"], default="name", help="Order of the crates in crates_info.json"
+    )
+    parser.add_argument("--compress", action="store_true", help="Also write a gzip-compressed selected_crates.json")
+    parser.add_argument("--keep-all-versions", action="store_true", help="Keep all versions of crates in the index")
+    parser.add_argument(
+        "--index-format",
+        choices=["files", "sqlite"],
+        default="files",
+        help="Format of the local registry index: a file per crate, or local-registry/index.sqlite",
+    )
+    parser.add_argument(
+        "--compile-check", action="store_true", help="Drop the crates that do not build alone with cargo check (slow)"
+    )
+    parser.add_argument("--compile-sample", type=int, default=0, metavar="N", help="Only compile-check N random crates")
+    parser.add_argument("--compile-target", metavar="TRIPLE", help="Target of the compile check")
+    parser.add_argument("--explain", metavar="CRATE", help="Print why a crate is selected and exit")
+    parser.add_argument("--check", action="store_true", help="Check that selected_crates.json is up to date")
+    parser.add_argument("--lockfile", metavar="FILE", help="Prefer the versions of this Cargo.lock")
+    parser.add_argument(
+        "--prefetch", action="store_true", help="Download the crates of the lockfile during the index update"
+    )
+    parser.add_argument("--previous", metavar="FILE", help="Fail if a crate version regresses from this selection")
+    parser.add_argument("--print-config", action="store_true", help="Print the effective configuration and exit")
+    parser.add_argument("--record", metavar="DIR", help="Record the HTTP responses and the index state")
+    parser.add_argument("--replay", metavar="DIR", help="Replay the HTTP responses of a recorded run")
+    parser.add_argument("--max-duration", type=int, metavar="SECONDS", help="Abort the run after this duration")
+    parser.add_argument(
+        "--changes",
+        nargs=2,
+        metavar=("OLD", "NEW"),
+        help="Print the crates changed in top-crates-index between two git refs and exit",
+    )
+    parser.add_argument(
+        "--changes-format", choices=["markdown", "json"], default="markdown", help="Format of --changes"
+    )
+    parser.add_argument(
+        "--index-paths", action="store_true", help="Print the index files of selected_crates.json and exit"
+    )
+    parser.add_argument(
+        "--merge-index",
+        nargs="+",
+        metavar=("OUTPUT", "INDEX"),
+        help="Merge the local registry indexes into OUTPUT and exit",
+    )
+
+    parser.add_argument(
+        "--github-annotations", action="store_true", help="Print the warnings as GitHub Actions annotations"
+    )
 
     parser.add_argument("-t", "--test", help=argparse.SUPPRESS)
 
     args = parser.parse_args()
 
-    a = TopCrates()
+    global GITHUB_ANNOTATIONS
+    GITHUB_ANNOTATIONS = args.github_annotations
+
+    a = TopCrates(args.config, args.index)
+
+    errors = a.validate()
+    for error in errors:
+        print(annotation("error", error), file=sys.stderr)
+    if errors:
+        fail(EXIT_CONFIG, f"invalid configuration {args.config}")
 
     a.verbose = args.verbose
 
+    if args.git_registry and args.index_format == "sqlite":
+        parser.error("--index-format sqlite cannot be used with --git-registry, Cargo reads the index files")
+    if args.replay and args.update:
+        parser.error("--replay cannot be used with --update, the index must stay at the recorded state")
+    if args.record:
+        a.http.record_dir = args.record
+        Path(args.record).mkdir(parents=True, exist_ok=True)
+    if args.replay:
+        a.http.replay_dir = args.replay
+    if args.top_file:
+        a.source = LocalFile(args.top_file)
+
+    if args.print_config:
+        print(json.dumps({"config": a.config(), "settings": vars(args)}, indent=2))
+        exit()
+
+    if args.changes:
+        old, new = index_changes("top-crates-index", *args.changes)
+        if args.changes_format == "json":
+            added, removed, changed = TopCrates.diff_selections(old, new)
+            changes = {
+                "added": dict((k, SemVer.sorted(new[k])) for k in added),
+                "removed": dict((k, SemVer.sorted(old[k])) for k in removed),
+                "updated": dict((k, {"old": SemVer.sorted(old[k]), "new": SemVer.sorted(new[k])}) for k in changed),
+            }
+            print(json.dumps(changes, indent=2))
+        else:
+            print(changes_markdown(old, new))
+        exit()
+
+    if args.index_paths:
+        for path in TopCrates.index_paths(TopCrates.load_selection()):
+            print(path)
+        exit()
+
+    # one run at a time in a directory: the indexes and the outputs would be garbled, the lock is released at exit
+    # the read-only modes do not take it: --print-config, --changes, --index-paths, --explain and --check without --update
+    if not (args.explain or args.check) or args.update:
+        lock = open(".top-crates.lock", "w")
+        try:
+            fcntl.flock(lock, fcntl.LOCK_EX | fcntl.LOCK_NB)
+        except BlockingIOError:
+            fail(EXIT_ERROR, f"another run is in progress in {Path.cwd()}")
+
+    if args.merge_index:
+        if len(args.merge_index) < 2:
+            parser.error("--merge-index needs an output directory and at least one index")
+        conflicts = merge_indexes(args.merge_index[0], args.merge_index[1:])
+        if conflicts:
+            fail(EXIT_ERROR, f"{conflicts} conflicting version{'s' if conflicts > 1 else ''} between the indexes")
+        exit()
+
+    phase = "startup"
+
+    if args.max_duration:
+
+        def _timeout(signum, frame):
+            sys.stdout.flush()
+            print()
+            fail(EXIT_TIMEOUT, f"run exceeded {args.max_duration} seconds, aborted during phase: {phase}")
+
+        signal.signal(signal.SIGALRM, _timeout)
+        signal.alarm(args.max_duration)
+
     if args.test:
         a.verbose = True
         crate = args.test.split(" ", 1)
@@ -715,51 +2553,177 @@ def main():
         exit()
 
     if args.audit:
-        if not Path("advisory-db").is_dir():
-            print("Downloading advisory-db")
-            git_cmd(["clone", "-b", "main", "https://github.com/rustsec/advisory-db"])
-        else:
-            # git_cmd(["fetch", "--all"], cwd="advisory-db")
-            # git_cmd(["reset", "--hard", "origin/master"], cwd="advisory-db")
-            pass
-
+        phase = "audit"
+        fetch_advisory_db()
         a.audit()
 
         exit()
 
-    if args.download or not Path("crates.json").is_file():
+    if args.top_only:
+        args.no_cookbook = args.no_curated = args.no_commands = True
+    partial_list = args.no_cookbook or args.no_curated or args.no_commands
+
+    if args.phase == "registry":
+        a.selected_crates = TopCrates.load_selection()
+        missing = [f"{name} {version}" for name, version in a.missing_versions()]
+        if missing and a.conf_missing_versions != "latest":
+            fail(EXIT_RESOLUTION, f"not in the index, resolve again: {', '.join(missing)}")
+        if not missing and not a.yanked_versions():
+            phase = "registry"
+            make_registry(a, args)
+            exit()
+        # the selection is outdated: resolve it again from the list of crates, and write all the outputs again
+        print("Selected versions have been yanked or are no more in the index, resolve again")
+
+
+    a.save_caches = not args.check
+
+    phase = "top crates"
+    if args.download or partial_list or not Path("crates.json").is_file():
         print("Build the top crates list")
         a.top_crates()
-        a.cookbook()
-        a.curated()
-        a.save("crates.json")
+        a.top_reverse_dependencies()
+        if not args.no_cookbook:
+            a.cookbook()
+        a.curated(not args.no_curated, not args.no_commands)
+        a.fetch_categories()
+        # a partial list is for comparison only, it must not replace the full one, nor the list of --explain
+        if not args.check and not args.explain and not partial_list:
+            a.save("crates.json")
     else:
         a.load("crates.json")
 
-    if args.update:
-        print("Update main index")
-        git_cmd(["fetch", "--all"], cwd="crates.io-index")
-        git_cmd(["reset", "--hard", "origin/master"], cwd="crates.io-index")
+    a.exclude_owners()
+    a.exclude_unpopular()
+    a.exclude_large()
 
-    a.resolve_deps()
+    if args.phase == "list":
+        exit()
 
-    if args.git_registry:
-        # not well supported, should git clone/git init before
-        if args.commit:
-            git_cmd(["clean", "-ffdx"], cwd="top-crates-index")
-            git_cmd(["reset", "--hard", "origin/master"], cwd="top-crates-index")
+    prefetch = None
+    if args.prefetch:
+        if not args.lockfile:
+            parser.error("--prefetch needs --lockfile")
+        prefetch = a.prefetch_crates(args.lockfile)
 
-        a.make_index("top-crates-index")
-        git_cmd(["status", "-s"], cwd="top-crates-index")
+    if args.update:
+        phase = "index update"
+        print("Update main index")
+        check_index(a.index_dir, a.conf_index_url)
+        update_index(a.index_dir)
+
+    # the index state is its HEAD, once updated
+    if args.record or args.replay:
+        head = ""
+        if a.index_dir.is_dir():
+            head = git_cmd(["rev-parse", "HEAD"], cwd=a.index_dir, capture_output=True, text=True).stdout.strip()
+        if args.record:
+            (Path(args.record) / "index-head").write_text(head + "\n")
+        else:
+            recorded_head = (Path(args.replay) / "index-head").read_text().strip()
+            if recorded_head != head:
+                warn(f"the index is at {head}, the run was recorded at {recorded_head}")
+
+    if args.lockfile:
+        a.load_lockfile(args.lockfile)
+
+    a.exclude_heavy()
+
+    phase = "resolution"
+    if args.explain:
+        a.resolve_deps(output=None)
+        path = a.explain(args.explain)
+        if path is None:
+            reason = a.excluded.get(TopCrates._normalize_name(args.explain), "not a dependency of the list")
+            print(f"{args.explain} is not selected: {reason}")
+            exit(EXIT_ERROR)
+        origin = a.origins.get(path[0], "requested")
+        print(f"{path[0]} ({origin})" + "".join(f" -> {crate}" for crate in path[1:]))
+        # platform-specific dependency edges
+        selected = dict((TopCrates._normalize_name(k), v) for k, v in a.selected_crates.items())
+        for parent, crate in zip(path, path[1:]):
+            for version in selected.get(parent, []):
+                for dep in a._index_versions(parent).get(version, {}).get("deps", []):
+                    if TopCrates._normalize_name(dep.get("package", dep["name"])) == crate and dep.get("target"):
+                        print(f"   {parent} {version} needs {crate} only for {dep['target']}")
+        exit()
 
-        if args.commit:
-            git_cmd(["add", "."], cwd="top-crates-index")
-            git_cmd(["commit", "-m", "Update top crates index"], cwd="top-crates-index")
-            git_cmd(["push", "origin", "master"], cwd="top-crates-index")
+    a.resolve_deps(output=None)
+    a.resolve_yanked()
+
+    if a.conf_require_curated and not partial_list:
+        missing = a.check_required()
+        for name, reason in missing:
+            print(annotation("error", f"required crate {name} is missing: {reason}"), file=sys.stderr)
+        if missing:
+            exit(EXIT_RESOLUTION)
+
+    if args.audit_fix:
+        phase = "audit"
+        fetch_advisory_db()
+        a.audit_fix()
+
+    if args.compile_check:
+        phase = "compile check"
+        a.compile_check(args.compile_sample, args.compile_target)
+
+    # compare the selection as it would be written, after the post-processing
+    if args.check:
+        old = json.load(open("selected_crates.json")) if Path("selected_crates.json").is_file() else {}
+        if Path("selected_crates.json").is_file() and Path("selected_crates.json").read_text() == a.selected_json():
+            print("selected_crates.json is up to date")
+            exit()
+        added, removed, changed = TopCrates.diff_selections(old, a.selected_crates)
+        print("selected_crates.json is not up to date")
+        for name in added:
+            print(f"  added:   {name} {' '.join(SemVer.sorted(a.selected_crates[name]))}")
+        for name in removed:
+            print(f"  removed: {name} {' '.join(SemVer.sorted(old[name]))}")
+        for name in changed:
+            old_versions = " ".join(SemVer.sorted(old[name]))
+            new_versions = " ".join(SemVer.sorted(a.selected_crates[name]))
+            print(f"  changed: {name} {old_versions} -> {new_versions}")
+        exit(EXIT_ERROR)
+
+    if args.previous:
+        regressions = TopCrates.check_regressions(json.load(open(args.previous)), a.selected_crates)
+        for name, old_version, new_version in regressions:
+            print(annotation("error", f"{name} regressed from {old_version} to {new_version}"), file=sys.stderr)
+        if regressions:
+            exit(EXIT_RESOLUTION)
+
+    for name, versions in a.duplicate_majors().items():
+        print(f"duplicate: {name} {' '.join(versions)}")
+        for version, required_by in versions.items():
+            print(f"   {version} required by: {', '.join(sorted(required_by)) or 'top crates'}")
+
+    Path("selected_crates.json").write_text(a.selected_json())
+    a.save_information(f"crates_info.{args.format}", header=args.info_header, sort=args.sort, format=args.format)
+    a.save_exclusions()
+    if args.audit_fix:
+        a.save_replacements()
+    if args.export_crates_io:
+        a.save_crates_io(args.export_crates_io)
+    if args.graph:
+        a.save_graph(args.graph)
+
+    if a.verbose:
+        print(f"{a.http.requests} requests, {a.http.bytes} bytes downloaded")
+
+    if args.compress:
+        a.save_compressed("selected_crates.json.gz")
+
+    if args.phase == "resolve":
+        exit()
 
-    else:
-        a.make_index()
-        a.download_crates(purge=args.purge)
+    phase = "registry"
+    if prefetch and prefetch.is_alive():
+        print("Waiting for the prefetched crates")
+        prefetch.join()
+    make_registry(a, args)
+
+    if a.verbose:
+        print(f"{a.http.requests} requests, {a.http.bytes} bytes downloaded, with the crates")
 
 
 def tests():
@@ -778,7 +2742,430 @@ def tests():
         == 3
     )
 
+    assert SemVer.sorted(["1.10.0", "1.2.0", "1.2.0-rc.1", "0.9.9"]) == ["0.9.9", "1.2.0-rc.1", "1.2.0", "1.10.0"]
+    assert [SemVer.compatibility(v) for v in ("1.2.3", "0.2.3", "0.0.3")] == ["1", "0.2", "0.0.3"]
+
+    assert TopCrates.diff_selections({"a": ["1.0.0"], "b": ["1.0.0"]}, {"b": ["1.1.0"], "c": ["0.1.0"]}) == (
+        ["c"],
+        ["a"],
+        ["b"],
+    )
+    assert TopCrates.check_regressions({"a": ["1.0.0", "2.0.0"], "b": []}, {"a": ["1.5.0"], "b": ["0.1.0"]}) == [
+        ("a", "2.0.0", "1.5.0")
+    ]
+
+    assert TopCrates._normalize_name("Foo_Bar-baz") == "foo-bar-baz"
+    assert TopCrates._crate_name({"id": "serde", "name": "serde"}) == TopCrates._crate_name({"id": "serde"}) == "serde"
+    assert TopCrates._prefix_name("a") == "1/a"
+    assert TopCrates._prefix_name("ab") == "2/ab"
+    assert TopCrates._prefix_name("abc") == "3/a/abc"
+    assert TopCrates._prefix_name("abcd") == "ab/cd/abcd"
+    assert TopCrates._prefix_name("serde") == "se/rd/serde"
+    assert TopCrates._prefix_name("Inflector") == "in/fl/inflector"
+    assert TopCrates._prefix_name("SERDE") == TopCrates._prefix_name("serde")
+    assert TopCrates._crate_url("https://dl/{prefix}/{crate}-{version}-{sha256-checksum}", "Serde", "1.0.0", "ab") == (
+        "https://dl/Se/rd/Serde-1.0.0-ab"
+    )
+    assert TopCrates._crate_url("https://dl/{lowerprefix}", "abc", "1.0.0") == "https://dl/3/a"
+    assert TopCrates._crate_url("https://dl", "a", "1.0.0") == "https://dl/a/1.0.0/download"
+    assert TopCrates.index_paths({"serde": [], "a": [], "Serde": []}) == ["1/a", "se/rd/serde"]
+
+    with tempfile.TemporaryDirectory() as tmp:
+        selection = Path(tmp) / "selected_crates.json"
+        selection.write_text('{"serde": ["1.0.0"], "log": ["0.4"]}')
+        with contextlib.redirect_stderr(io.StringIO()) as err:
+            try:
+                TopCrates.load_selection(selection)
+                assert False
+            except SystemExit as e:
+                assert e.code == EXIT_ERROR
+        assert "log has an invalid version '0.4'" in err.getvalue()
+
+    assert changes_markdown({"a": ["1.0.0"], "b": ["1.0.0"]}, {"b": ["1.1.0"], "c": ["0.1.0"]}).splitlines() == [
+        "### Added",
+        "",
+        "- `c` 0.1.0",
+        "",
+        "### Removed",
+        "",
+        "- `a` 1.0.0",
+        "",
+        "### Updated",
+        "",
+        "- `b` 1.0.0 → 1.1.0",
+    ]
+
+    # offline resolution with a fixture index
+    with tempfile.TemporaryDirectory() as tmp:
+        tmp = Path(tmp)
+
+        def _index(name, *versions):
+            f = tmp / "index" / TopCrates._prefix_name(name)
+            f.parent.mkdir(parents=True, exist_ok=True)
+            lines = [json.dumps({"name": name, "vers": v, "deps": d, "yanked": False}) for v, d in versions]
+            f.write_text("\n".join(lines) + "\n")
+
+        def _dep(name, req, target=None):
+            return {"name": name, "req": req, "kind": "normal", "optional": False, "target": target}
+
+        _index("alpha", ("1.0.0", []), ("1.1.0", [_dep("beta", "^0.1")]), ("2.0.0-rc.1", []))
+        _index("beta", ("0.1.0", []), ("0.1.5", [_dep("gamma", "*", "cfg(windows)")]), ("0.2.0", []))
+        _index("gamma", ("0.1.0-alpha.1", []))
+        _index("delta", ("1.0.0", [_dep("beta", "^0.2")]))
+        _index("tool", ("1.0.0", [_dep("evil", "^1")]))
+        _index("evil", ("1.0.0", []))
+        (tmp / "top-crates.toml").write_text('exclusions = ["evi*"]\n')
+
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        assert t.validate() == []
+        t.conf_additions, t.conf_max_depth, t.conf_zero_major = ["ok", ""], -1, "no"
+        assert len(t.validate()) == 3
+        (tmp / "extra.toml").write_text('additions = ["extra"]\n')
+        (tmp / "include.toml").write_text(f"include = [{json.dumps(str(tmp / 'extra*.toml'))}]\n")
+        assert TopCrates(tmp / "include.toml", tmp / "index").conf_additions == ["extra"]
+        (tmp / "invalid.toml").write_text('exclusions = [1]\nallow = "a*"\naliases = { a = 2 }\n')
+        assert len(TopCrates(tmp / "invalid.toml", tmp / "index").validate()) == 3
+        t.conf_additions, t.conf_max_depth, t.conf_zero_major = [], 0, ""
+        for name in ("alpha", "Delta", "tool"):
+            t.add(name)
+
+        # different spellings of a name are the same crate
+        t.add("ALPHA", "^1.0")
+        assert t.crates["alpha"] == {"latest", "^1.0"}
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.resolve_deps(output=None)
+
+        assert dict((k, SemVer.sorted(v)) for k, v in t.selected_crates.items()) == {
+            "alpha": ["1.1.0"],
+            "beta": ["0.1.5", "0.2.0"],
+            "gamma": ["0.1.0-alpha.1"],
+            "delta": ["1.0.0"],
+            "tool": ["1.0.0"],
+        }
+        assert t.excluded == {"evil": "excluded by configuration"}
+        assert t.graph["alpha"] == {"beta"} and t.graph["tool"] == {"evil"}
+        assert t.explain("gamma") == ["alpha", "beta", "gamma"] and t.explain("evil") is None
+        assert t.duplicate_majors() == {"beta": {"0.1.5": {"alpha 1.1.0"}, "0.2.0": {"delta 1.0.0"}}}
+        t.save_graph(tmp / "graph.json")
+        graph = json.loads((tmp / "graph.json").read_text())
+        assert [(d["name"], d["version"]) for d in graph["tool"]["1.0.0"]] == [("evil", None)]
+        assert graph["beta"]["0.1.5"][0]["version"] == "0.1.0-alpha.1"
+
+        # only the changed files of the registry index are written
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            t.make_index(tmp / "registry")
+            delta = t.selected_crates.pop("delta")
+            t.make_index(tmp / "registry")
+            t.selected_crates["delta"] = delta
+        assert out.getvalue().splitlines()[-1] == "index: 0 files written, 4 unchanged, 1 removed"
+        assert not (tmp / "registry" / TopCrates._prefix_name("delta")).exists()
+
+        # the index as a sqlite database
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.make_index(tmp / "registry", format="sqlite")
+        with contextlib.closing(sqlite3.connect(tmp / "registry.sqlite")) as db:
+            rows = db.execute("SELECT name, version FROM crates ORDER BY name, version").fetchall()
+        assert rows[:3] == [("alpha", "1.1.0"), ("beta", "0.1.5"), ("beta", "0.2.0")] and len(rows) == 6
+
+        # a selected version no more in the index
+        t.selected_crates["alpha"] = ["1.0.1"]
+        (tmp / "registry" / TopCrates._prefix_name("beta")).unlink()
+        with contextlib.redirect_stdout(io.StringIO()), contextlib.redirect_stderr(io.StringIO()) as err:
+            try:
+                t.make_index(tmp / "registry")
+                assert False
+            except SystemExit as e:
+                assert e.code == EXIT_RESOLUTION
+            assert "alpha 1.0.1" in err.getvalue()
+            assert not (tmp / "registry" / TopCrates._prefix_name("beta")).exists()
+        assert t.missing_versions() == [("alpha", "1.0.1")]
+        t.selected_crates["alpha"] = ["1.1.0"]
+
+        t.summaries = {
+            "beta": {"downloads": 10},
+            "delta": {"downloads": 10},
+            "tool": {"downloads": 20, "homepage": "https://tool.rs"},
+        }
+        t.save_information(tmp / "crates_info.json", sort="downloads")
+        info = json.loads((tmp / "crates_info.json").read_text())
+        assert [c["name"] for c in info] == ["tool", "beta", "beta", "delta", "alpha", "gamma"]
+        assert info[0]["homepage"] == "https://tool.rs" and "homepage" not in info[1]
+        assert info[1]["documentation"] == "https://docs.rs/beta/0.1.5"
+
+        t.save_compressed(tmp / "selected_crates.json.gz")
+        assert gzip.decompress((tmp / "selected_crates.json.gz").read_bytes()).decode() == t.selected_json()
+
+        # two runs give the same bytes, whatever the order of the selection
+        os.environ["SOURCE_DATE_EPOCH"], selected = "1700000000", t.selected_crates
+        try:
+            t.save_compressed(tmp / "run1.json.gz")
+            t.selected_crates = dict(reversed(list(selected.items())))
+            t.save_compressed(tmp / "run2.json.gz")
+        finally:
+            del os.environ["SOURCE_DATE_EPOCH"]
+            t.selected_crates = selected
+        assert (tmp / "run1.json.gz").read_bytes() == (tmp / "run2.json.gz").read_bytes()
+        assert int.from_bytes((tmp / "run1.json.gz").read_bytes()[4:8], "little") == 1700000000
+
+        t.conf_core, t.conf_core_top = ["Gamma"], 1
+        assert t.core_crates() == {"gamma", "tool"}
+        t.conf_core, t.conf_core_top = [], 0
+
+        t.conf_max_duplicate_versions = 0
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            t.save_information(tmp / "crates_info.json")
+        info = json.loads((tmp / "crates_info.json").read_text())
+        assert [c["version"] for c in info if c["name"] == "beta"] == ["0.2.0"]
+        assert out.getvalue() == "WARNING: max-duplicate-versions: dropped beta 0.1.5\n"
+        t.conf_max_duplicate_versions = None
+
+        t.origins["tool"] = "command"
+        t.save_information(tmp / "crates_info.json")
+        kinds = dict((c["name"], c["kind"]) for c in json.loads((tmp / "crates_info.json").read_text()))
+        assert kinds["tool"] == "command" and kinds["alpha"] == "library"
+
+        checksum = (tmp / "crates_info.json.sha256").read_text().split()
+        assert checksum == [hashlib.sha256((tmp / "crates_info.json").read_bytes()).hexdigest(), "crates_info.json"]
+
+        t.origins["gamma"] = "hidden"
+        t.save_information(tmp / "crates_info.json")
+        assert "gamma" not in [c["name"] for c in json.loads((tmp / "crates_info.json").read_text())]
+        del t.origins["gamma"]
+
+        t.save_information(tmp / "crates_info.toml", header=True, format="toml")
+        toml = tomli.loads((tmp / "crates_info.toml").read_text())
+        assert toml["meta"]["crates"] == 5 and [c["name"] for c in toml["crates"]][:3] == ["alpha", "beta", "beta"]
+        assert toml["crates"][0]["version"] == "1.1.0" and "description" not in toml["crates"][0]
+
+        # merging indexes: union of the versions, conflicts reported
+        other = tmp / "other" / TopCrates._prefix_name("beta")
+        other.parent.mkdir(parents=True)
+        other.write_text(
+            json.dumps({"name": "beta", "vers": "0.3.0", "deps": [], "yanked": False})
+            + "\n"
+            + json.dumps({"name": "beta", "vers": "0.1.0", "deps": [], "yanked": True})
+            + "\n"
+        )
+        with contextlib.redirect_stdout(io.StringIO()):
+            assert merge_indexes(tmp / "merged", [tmp / "index", tmp / "other"]) == 1
+        beta = (tmp / "merged" / TopCrates._prefix_name("beta")).read_text().splitlines()
+        assert [json.loads(line)["vers"] for line in beta] == ["0.1.0", "0.1.5", "0.2.0", "0.3.0"]
+        assert not json.loads(beta[0])["yanked"]
+        assert (tmp / "merged" / TopCrates._prefix_name("evil")).is_file()
+
+        # a version of a top crate published too recently is not used yet, curated crates are exempt
+        fresh = tmp / "index" / TopCrates._prefix_name("fresh")
+        fresh.parent.mkdir(parents=True, exist_ok=True)
+        old, new = datetime(2020, 1, 1, tzinfo=timezone.utc), datetime.now(timezone.utc)
+        fresh.write_text(
+            "".join(
+                json.dumps({"name": "fresh", "vers": v, "deps": [], "yanked": False, "pubtime": d.isoformat()}) + "\n"
+                for v, d in (("1.0.0", old), ("1.1.0", new))
+            )
+        )
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_version_settle_days = 7
+        t.add("fresh", "1.1.0", "top")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.resolve_deps(output=None)
+        assert t.selected_crates == {"fresh": ["1.0.0"]}
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_version_settle_days = 7
+        t.add("fresh", origin="curated")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.resolve_deps(output=None)
+        assert t.selected_crates == {"fresh": ["1.1.0"]}
+
+        # a top crate also listed in hidden-additions stays in crates_info
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.add("zero", "0.2.0", "top")
+        t.conf_hidden_additions = ["zero", "fresh"]
+        t.curated()
+        assert t.origins == {"zero": "top", "fresh": "hidden"}
+
+        # the version given by crates.io for a top crate is filtered like the latest one
+        _index("zero", ("0.1.0", []), ("0.2.0", []))
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_zero_major, t.conf_min_crates = "exclude", 0
+        t.add("zero", "0.2.0", "top")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.resolve_deps(output=None)
+        assert t.selected_crates == {} and t.excluded == {"zero": "0.2.0 is a 0.x version"}
+
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_max_version_age, t.conf_min_crates = 365, 0
+        t.add("fresh", "1.0.0", "top")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.resolve_deps(output=None)
+        assert t.selected_crates == {} and t.excluded["fresh"].startswith("1.0.0 is ")
+
+        # the versions affected by an advisory are replaced, unless other crates depend on them
+        for name, patched in (("beta", "0.1.5"), ("zero", "0.2.0"), ("evil", "2.0.0")):
+            (tmp / "advisory-db" / "crates" / name).mkdir(parents=True)
+            (tmp / "advisory-db" / "crates" / name / "RUSTSEC-0000-0000.md").write_text(
+                f'```toml\n[advisory]\nid = "RUSTSEC-0000-0000"\n\n[versions]\npatched = [">= {patched}"]\n```\n'
+            )
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.selected_crates = {
+            "alpha": ["1.1.0"],
+            "beta": ["0.1.0"],
+            "zero": ["0.1.0"],
+            "tool": ["1.0.0"],
+            "evil": ["1.0.0"],
+        }
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            t.audit_fix(tmp / "advisory-db")
+        assert t.selected_crates == {
+            "alpha": ["1.1.0"],
+            "beta": ["0.1.5"],
+            "zero": ["0.2.0"],
+            "tool": ["1.0.0"],
+            "evil": ["1.0.0"],
+        }
+        assert "WARNING: evil 1.0.0 is affected by RUSTSEC-0000-0000, but other crates depend on it" in out.getvalue()
+        assert t.excluded == {} and [(r["name"], r["replacement"]) for r in t.replacements] == [
+            ("beta", "0.1.5"),
+            ("zero", "0.2.0"),
+        ]
+
+        # the versions that do not build alone are dropped, unless other crates depend on them
+        checked = []
+
+        def _cargo(command, cwd, **kwargs):
+            manifest = (Path(cwd) / "Cargo.toml").read_text()
+            checked.append(manifest.splitlines()[-1])
+            failed = '"beta"' in manifest or '"zero"' in manifest
+            return subprocess.CompletedProcess(command, 101 if failed else 0, "", "error")
+
+        run, subprocess.run = subprocess.run, _cargo
+        try:
+            t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+            t.selected_crates = {"alpha": ["1.1.0"], "beta": ["0.1.5"], "zero": ["0.2.0"]}
+            t.graph = {"alpha": {"beta"}}
+            with contextlib.redirect_stdout(io.StringIO()) as out:
+                assert t.compile_check() == [("beta", "0.1.5"), ("zero", "0.2.0")]
+            assert checked == ['"alpha" = "=1.1.0"', '"beta" = "=0.1.5"', '"zero" = "=0.2.0"']
+            assert t.selected_crates == {"alpha": ["1.1.0"], "beta": ["0.1.5"]}
+            assert t.excluded == {"zero": "0.2.0 fails to compile"}
+            assert "WARNING: beta 0.1.5 fails to compile, but other crates depend on it" in out.getvalue()
+
+            checked.clear()
+            with contextlib.redirect_stdout(io.StringIO()):
+                t.compile_check(sample=1)
+            assert len(checked) == 1
+        finally:
+            subprocess.run = run
+
+        # the large top crates are dropped, the curated ones are exempt
+        def _get(url, **kwargs):
+            r = requests.Response()
+            r.status_code = 200
+            size = 10**6 if url.endswith(("/alpha/1.1.0", "/delta/1.0.0")) else 1000
+            r._content = json.dumps({"version": {"crate_size": size}}).encode()
+            return r
+
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_max_crate_size, t.http.get = 10**5, _get
+        t.summaries = {"alpha": {"max_version": "1.1.0"}, "beta": {"max_version": "0.2.0"}}
+        t.add("alpha", origin="top")
+        t.add("beta", origin="top")
+        t.add("delta", origin="curated")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.exclude_large(tmp / "crate_sizes.json")
+        assert sorted(t.crates) == ["beta", "delta"] and t.excluded == {"alpha": "1.1.0 is 1000000 bytes"}
+        assert (tmp / "crate_sizes.json").is_file()
+
+        # a read-only run, e.g. --check, does not write the caches
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_max_crate_size, t.http.get, t.save_caches = 10**5, _get, False
+        t.summaries = {"alpha": {"max_version": "1.1.0"}}
+        t.add("alpha", origin="top")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.exclude_large(tmp / "read_only_sizes.json")
+        assert t.excluded == {"alpha": "1.1.0 is 1000000 bytes"} and not (tmp / "read_only_sizes.json").exists()
+
+        # the plan fetches the top crates of each category from the source
+        class _Source:
+            def fetch(self, count, category="", verbose=False):
+                top = [{"id": "alpha", "max_version": "2.0.0-rc.1", "max_stable_version": "1.1.0"}, {"id": "beta"}]
+                return top[:count]
+
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.source = _Source()
+        t.fetch_plan([(2, ""), (1, "parsing")])
+        assert t.crates == {"alpha": {"1.1.0", "2.0.0-rc.1"}, "beta": {"latest"}}
+        assert t.origins == {"alpha": "top", "beta": "top"} and t.categories == {"alpha": {"parsing"}}
+
+        # deny by default: only the allowed top crates and the explicit requests are kept
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_deny_by_default, t.allow = True, [re.compile("^al.*$")]
+        with contextlib.redirect_stdout(io.StringIO()):
+            for name in ("alpha", "beta", "delta"):
+                t.add(name, origin="top")
+            t.add("delta", origin="curated")
+        assert sorted(t.crates) == ["alpha", "delta"] and t.excluded == {"beta": "not in the allow list"}
+        # the dependencies of the allowed crates are kept, even if they were denied as top crates
+        t.crates.clear()
+        t.add("alpha", origin="top")
+        with contextlib.redirect_stdout(io.StringIO()):
+            t.resolve_deps(output=None)
+        assert sorted(t.selected_crates) == ["alpha", "beta", "gamma"] and t.excluded == {}
+
+        # a locked version yanked since is not preferred anymore
+        yank = tmp / "index" / TopCrates._prefix_name("yank")
+        yank.parent.mkdir(parents=True, exist_ok=True)
+        yank.write_text(
+            "".join(
+                json.dumps({"name": "yank", "vers": v, "deps": [], "yanked": y}) + "\n"
+                for v, y in (("1.0.0", False), ("1.0.1", True))
+            )
+        )
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.locked["yank"].add("1.0.1")
+        t.add("yank", "^1.0")
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            t.resolve_deps(output=None)
+            assert t.yanked_versions() == [("yank", "1.0.1")]
+            t.resolve_yanked()
+        assert t.selected_crates == {"yank": ["1.0.0"]} and t.yanked_versions() == []
+        assert "WARNING: yank 1.0.1 has been yanked, resolve again" in out.getvalue()
+
+        # the crates that depend on a heavy crate are dropped, the first resolution leaves no exclusion
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.conf_heavy_crates, t.conf_exclude_edges = ["gamma"], [["beta", "gamma"]]
+        t.add("alpha", origin="top")
+        t.add("tool", origin="top")
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            t.exclude_heavy()
+            assert t.excluded == {"alpha": "depends on gamma"}
+            t.resolve_deps(output=None)
+        assert "excluded: evil (excluded by configuration)" in out.getvalue()
+
+        # a version required only by a dev-dependency is labelled so
+        _index("devtool", ("1.0.0", [dict(_dep("beta", "^0.2"), kind="dev")]))
+        t = TopCrates(tmp / "top-crates.toml", tmp / "index")
+        t.selected_crates = {"alpha": ["1.1.0"], "beta": ["0.1.5", "0.2.0"], "devtool": ["1.0.0"]}
+        assert t.duplicate_majors() == {"beta": {"0.1.5": {"alpha 1.1.0"}, "0.2.0": {"devtool 1.0.0 (dev)"}}}
+
+    # warnings and errors as GitHub Actions annotations
+    global GITHUB_ANNOTATIONS
+    with contextlib.redirect_stdout(io.StringIO()) as out:
+        warn("plain")
+        GITHUB_ANNOTATIONS = True
+        warn("100% broken\nagain")
+        error = annotation("error", "100% broken\r\nagain")
+        GITHUB_ANNOTATIONS = False
+    assert out.getvalue() == "WARNING: plain\n::warning::100%25 broken%0Aagain\n"
+    assert error == "::error::100%25 broken%0D%0Aagain" and annotation("error", "plain") == "ERROR: plain"
+
 
 if __name__ == "__main__":
     tests()
-    main()
+    try:
+        main()
+    except tomli.TOMLDecodeError as e:
+        fail(EXIT_CONFIG, f"invalid configuration: {e}")
+    except requests.exceptions.RequestException as e:
+        fail(EXIT_NETWORK, e)
+    except OSError as e:
+        fail(EXIT_IO, e)